@@ -8,6 +8,6 @@ pub use crate::types::core::*;
 pub use crate::types::data::{ResourceData, RD, FromRD};
 pub use crate::types::decode::{Decode, Buf};
 pub use crate::types::parse::ParserBuffer;
-pub use crate::types::newval::{SchemaParser, data_parse};
+pub use crate::types::newval::{SchemaParser, data_parse, schema_is_superset, data_convert};
 pub use crate::types::encode::Encode;
 pub use crate::types::native::*;
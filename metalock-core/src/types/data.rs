@@ -96,6 +96,41 @@ impl RD {
     pub fn none() -> Self {
         RD::Option(OptPackedPtr::null())
     }
+    pub fn tuple(items: Vec<RD>) -> Self {
+        pp(TUPLE::ID, 0, Some(items))
+    }
+    pub fn as_bytes(&self) -> Option<&'static [u8]> {
+        match self {
+            RD::String(_) => Some(self._as::<&'static String>().as_bytes()),
+            RD::Buffer(_) => Some(&self._as::<&'static Buffer>().0),
+            RD::Buf32(_) => Some(self._as::<&'static [u8; 32]>()),
+            _ => None
+        }
+    }
+}
+
+/*
+ * Values of the same type are ordered, lists and tuples lexicographically
+ */
+impl PartialOrd for RD {
+    fn partial_cmp(&self, other: &RD) -> Option<std::cmp::Ordering> {
+        match (self, other) {
+            (RD::Unit(), RD::Unit()) => Some(std::cmp::Ordering::Equal),
+            (RD::U8(a), RD::U8(b)) => a.partial_cmp(b),
+            (RD::U16(a), RD::U16(b)) => a.partial_cmp(b),
+            (RD::U32(a), RD::U32(b)) => a.partial_cmp(b),
+            (RD::U64(a), RD::U64(b)) => (**a).partial_cmp(&**b),
+            (RD::U128(a), RD::U128(b)) => (**a).partial_cmp(&**b),
+            (RD::Bool(a), RD::Bool(b)) => a.partial_cmp(b),
+            (RD::String(a), RD::String(b)) => (**a).partial_cmp(&**b),
+            (RD::Buffer(a), RD::Buffer(b)) => (**a).partial_cmp(&**b),
+            (RD::Buf32(a), RD::Buf32(b)) => (**a).partial_cmp(&**b),
+            (RD::Option(a), RD::Option(b)) => a.as_ref().partial_cmp(&b.as_ref()),
+            (RD::List(a), RD::List(b)) => (**a).partial_cmp(&**b),
+            (RD::Tuple(a), RD::Tuple(b)) => (**a).partial_cmp(&**b),
+            _ => None
+        }
+    }
 }
 
 const _: () = assert!(std::mem::size_of::<RD>() == 8);
@@ -200,6 +235,7 @@ impl_from_rd3!([], u128, (RD::U128(b)) => **b);
 impl_from_rd3!([], bool, (RD::Bool(b)) => *b);
 impl_from_rd3!([], &'static String, STRING, |p, _e| p);
 impl_from_rd3!([], &'static Buffer, BUFFER, |p, _e| p);
+impl_from_rd3!([], &'static [u8; 32], BUF32, |p, _e| p);
 //impl_from_rd3!([], &Vec<RD>, LIST, |p, _e| p);
 impl_from_rd3!([T], Option<T>, (RD::Option(p)) => p.as_ref().map(T::from_rd3));
 impl_from_rd3!([], &'static EncodedFunction, FUNCTION, |p, _e| p);
@@ -77,7 +77,7 @@ impl_serialize_any!([], RD, |self| {
         RD::Buf32(p) => p.rd_encode(),
         RD::Option(o) => o.as_ref().rd_encode(),
         RD::List(v) => v.rd_encode(),
-        RD::Tuple(v) => v.rd_encode(),
+        RD::Tuple(v) => v.iter().flat_map(|i| i.rd_encode()).collect(),
         RD::Function(f) => f.rd_encode(),
         RD::Native(_) => panic!("no serialize for native"),
     }
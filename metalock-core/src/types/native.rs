@@ -1,13 +1,6 @@
 
 use solana_program::{instruction::AccountMeta, pubkey::Pubkey};
 
-macro_rules! offset_of {
-    ($struct:ty, $field:ident) => {{
-        let dummy_ptr = std::ptr::null::<$struct>();
-        let field_ptr = unsafe { &(*dummy_ptr).$field as *const _ };
-        (field_ptr as usize) - (dummy_ptr as usize)
-    }};
-}
 
 use super::core::*;
 use super::tags::*;
@@ -25,6 +18,18 @@ use paste::paste;
 
 pub trait NativeData: SchemaType {}
 
+/*
+ * Native structs that can be viewed in place in account data, see
+ * account_data_as. They must not hold pointers (Vec, Buffer), since those
+ * would be read from the account; ACCOUNT_NATIVE also refuses such schemas.
+ * Fields are read unaligned, and bools as bytes.
+ */
+/// # Safety
+///
+/// Field offsets come from offset_of!, so the struct must be laid out like
+/// the account data: `#[repr(C, packed)]`, or `#[repr(C)]` without padding.
+pub unsafe trait NativePod: NativeData {}
+
 
 
 
@@ -33,11 +38,11 @@ macro_rules! rr_native_struct {
         paste! {
             impl RR<$struct> {
             $(  pub fn [<get_ $field>](&self) -> RR<$type> {
-                    let offset = offset_of!($struct, $field);
+                    let offset = std::mem::offset_of!($struct, $field);
                     RR::new(GetStructField(self.clone(), $idx, offset as u32, PhantomData::default()))
                 }
                 pub fn [<set_ $field>](&self, val: impl ToRR<$type>) -> Self {
-                    let offset = offset_of!($struct, $field);
+                    let offset = std::mem::offset_of!($struct, $field);
                     RR::new(SetStructField(self.clone(), $idx, offset as u32, val.rr()))
                 } )*
             }
@@ -52,9 +57,9 @@ macro_rules! rr_native_struct {
             }
         }
         impl NativeData for $struct {}
-        impl Into<RD> for $struct {
-            fn into(self) -> RD {
-                Native::from(self).into()
+        impl From<$struct> for RD {
+            fn from(s: $struct) -> RD {
+                Native::from(s).into()
             }
         }
     };
@@ -68,6 +73,8 @@ rr_native_struct!(
         2 is_writable: bool
     }
 );
+// repr(C) without padding
+unsafe impl NativePod for AccountMeta {}
 
 
 #[derive(Clone, Debug)]
@@ -85,3 +92,28 @@ rr_native_struct!(
     }
 );
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As it would be in account data
+    #[repr(C, packed)]
+    #[derive(Clone, Copy, Debug)]
+    struct Balance { flag: u8, amount: u64, count: u16 }
+    rr_native_struct!(Balance { 0 flag: u8, 1 amount: u64, 2 count: u16 });
+    unsafe impl NativePod for Balance {}
+
+    #[test]
+    fn test_pod_fields() {
+        let data = [&[1u8][..], &u64::MAX.to_le_bytes(), &300u16.to_le_bytes()].concat();
+        let schema = Balance::to_schema();
+        assert_eq!(Native::pod_size(&schema), Some(data.len()));
+
+        let native = Native(schema, data.as_ptr());
+        let field = |idx, offset: usize| native.get_struct_field(idx, offset as u32).unwrap();
+        assert_eq!(field(0, std::mem::offset_of!(Balance, flag)), 1u8.into());
+        assert_eq!(field(1, std::mem::offset_of!(Balance, amount)), u64::MAX.into());
+        assert_eq!(field(2, std::mem::offset_of!(Balance, count)), 300u16.into());
+    }
+}
@@ -1,5 +1,5 @@
 
-use super::{data::*, decode::*, parse::*, core::*, tags::*, schema::*};
+use super::{data::*, decode::*, encode::*, parse::*, core::*, tags::*, schema::*};
 use super::macros::{impl_deref, impl_into};
 
 
@@ -20,10 +20,7 @@ pub fn data_parse(buf: Parser) -> R<RD> {
             tag::BOOL::ID => RD::Bool(rdd(data)?),
             tag::STRING::ID => rdd::<String>(data)?.into(),
             tag::BUFFER::ID => rdd::<Buffer>(data)?.into(),
-            tag::BUF32::ID => {
-                let p = (&data.take::<32>()?) as *const [u8; 32];
-                p.into()
-            },
+            tag::BUF32::ID => data.take::<32>()?.into(),
             tag::OPTION::ID => {
                 if bool::rd_decode(data)? {
                     Some(parse_inner(schema, data)?)
@@ -40,9 +37,15 @@ pub fn data_parse(buf: Parser) -> R<RD> {
             },
             tag::TUPLE::ID => {
                 let n = schema.next();
-                u16::rd_decode(schema)?;
-                //let items = (0..n).map(|_| parse_inner(schema, data));
-                panic!("tuple") // RD::Tuple(items.collect::<R<Vec<_>>>()?)
+                let len = schema.take_u16() as usize;
+                let mut item_schema = SchemaParser(ParserBuffer(&schema.0[..len]));
+                schema.skip_bytes(len);
+                let items = (0..n).map(|_| {
+                    let r = parse_inner(&mut item_schema.0.clone(), data);
+                    item_schema.skip_schema(1);
+                    r
+                });
+                RD::tuple(items.collect::<R<Vec<_>>>()?)
             },
             tag::RSTRUCT::ID => panic!("no parse for native"),
             tag::FUNCTION::ID => {
@@ -59,6 +62,74 @@ pub fn data_parse(buf: Parser) -> R<RD> {
 }
 
 
+/*
+ * Whether a value has a schema. Function values match any function schema,
+ * since they don't carry their types.
+ */
+pub fn data_matches(schema: &Schema, rd: &RD) -> bool {
+    let mut schema = schema.0.as_slice();
+    matches(&mut schema, rd) && schema.is_empty()
+}
+
+fn matches(schema: Buf, rd: &RD) -> bool {
+    if let RD::Native(native) = rd {
+        let native = &native.0.0;
+        if !schema.starts_with(native) {
+            return false;
+        }
+        *schema = &schema[native.len()..];
+        return true;
+    }
+    if schema.first() != Some(&rd.tag()) {
+        return false;
+    }
+    *schema = &schema[1..];
+    match rd {
+        RD::Option(o) => match o.as_ref() {
+            Some(item) => matches(schema, item),
+            None => { skip_schema(schema); true }
+        },
+        RD::List(items) => {
+            let item = *schema;
+            skip_schema(schema);
+            items.iter().all(|i| matches(&mut { item }, i))
+        },
+        RD::Tuple(items) => match take_tuple(schema) {
+            Ok((mut s, n)) => n as usize == items.len() && items.iter().all(|i| matches(&mut s, i)),
+            Err(_) => false
+        },
+        RD::Function(_) => { skip_schema(schema); skip_schema(schema); true },
+        _ => true
+    }
+}
+
+
+// Whether values of a schema can hold a function
+pub fn schema_has_function(schema: &Schema) -> bool {
+    has_function(&mut schema.0.as_slice())
+}
+
+fn has_function(schema: Buf) -> bool {
+    match schema.first() {
+        Some(&tag::FUNCTION::ID) => { skip_schema(schema); true },
+        Some(&tag::OPTION::ID) | Some(&tag::LIST::ID) => { *schema = &schema[1..]; has_function(schema) },
+        Some(&tag::TUPLE::ID) => {
+            *schema = &schema[1..];
+            match take_tuple(schema) {
+                Ok((mut items, n)) => (0..n).fold(false, |found, _| has_function(&mut items) | found),
+                Err(_) => false
+            }
+        },
+        _ => { skip_schema(schema); false }
+    }
+}
+
+
+/*
+ * A superset has the same shape, except that tuples may have extra fields at
+ * the end. Data can be converted either way with data_convert. Functions
+ * can't be upgraded.
+ */
 pub fn schema_is_superset(subset: Parser, superset: Parser) -> bool {
     match (subset.next(), superset.next()) {
         (tag::OPTION::ID, tag::OPTION::ID) => schema_is_superset(subset, superset),
@@ -77,13 +148,146 @@ pub fn schema_is_superset(subset: Parser, superset: Parser) -> bool {
                 false
             }
         },
-        _ => { return false; }
+        (a, b) => a == b && a <= tag::BUF32::ID
     }
 }
 
 
+// Re-encode data to a superset or subset of its schema: tuple fields missing
+// from `from` get default values, and those missing from `to` are dropped
+pub fn data_convert(from: &Schema, to: &Schema, data: &[u8]) -> R<Vec<u8>> {
+    let mut out = vec![];
+    convert(&mut from.0.as_slice(), &mut to.0.as_slice(), &mut { data }, &mut out)?;
+    Ok(out)
+}
 
+fn convert(from: Buf, to: Buf, data: Buf, out: &mut Vec<u8>) -> R<()> {
+    let t = take_n(from, 1)?[0];
+    if take_n(to, 1)?[0] != t {
+        return Err("data_convert: incompatible schemas".into());
+    }
+    if let Some(n) = fixed_size(t) {
+        out.extend(take_n(data, n)?);
+        return Ok(());
+    }
+    match t {
+        tag::STRING::ID | tag::BUFFER::ID => {
+            let n = take_u16(&mut { *data })?;
+            out.extend(take_n(data, 2 + n as usize)?);
+        },
+        tag::OPTION::ID => {
+            let some = take_n(data, 1)?[0];
+            out.push(some);
+            if some > 0 {
+                convert(from, to, data, out)?;
+            } else {
+                skip_schema(from);
+                skip_schema(to);
+            }
+        },
+        tag::LIST::ID => {
+            let n = take_u16(data)?;
+            out.extend(n.rd_encode());
+            for _ in 0..n {
+                convert(&mut { *from }, &mut { *to }, data, out)?;
+            }
+            skip_schema(from);
+            skip_schema(to);
+        },
+        tag::TUPLE::ID => {
+            let (mut items_a, na) = take_tuple(from)?;
+            let (mut items_b, nb) = take_tuple(to)?;
+            for i in 0..na.max(nb) {
+                if i < na && i < nb {
+                    convert(&mut items_a, &mut items_b, data, out)?;
+                } else if i < na {
+                    let mut same = items_a;
+                    convert(&mut items_a, &mut same, data, &mut vec![])?;
+                } else {
+                    data_default(&mut items_b, out)?;
+                }
+            }
+        },
+        tag::FUNCTION::ID => {
+            skip_schema(from);
+            skip_schema(from);
+            skip_schema(to);
+            skip_schema(to);
+            let n = take_u16(&mut data.get(2..).unwrap_or_default())?;
+            out.extend(take_n(data, 4 + n as usize)?);
+        },
+        t => return Err(format!("data_convert: unsupported tag {}", t)),
+    }
+    Ok(())
+}
+
+// Zero, empty or None
+fn data_default(schema: Buf, out: &mut Vec<u8>) -> R<()> {
+    let t = take_n(schema, 1)?[0];
+    if let Some(n) = fixed_size(t) {
+        out.extend(vec![0; n]);
+        return Ok(());
+    }
+    match t {
+        tag::STRING::ID | tag::BUFFER::ID => out.extend([0, 0]),
+        tag::OPTION::ID => {
+            out.push(0);
+            skip_schema(schema);
+        },
+        tag::LIST::ID => {
+            out.extend([0, 0]);
+            skip_schema(schema);
+        },
+        tag::TUPLE::ID => {
+            let (mut items, n) = take_tuple(schema)?;
+            for _ in 0..n {
+                data_default(&mut items, out)?;
+            }
+        },
+        t => return Err(format!("data_convert: no default for tag {}", t)),
+    }
+    Ok(())
+}
+
+fn fixed_size(t: u8) -> Option<usize> {
+    match t {
+        tag::UNIT::ID => Some(0),
+        tag::U8::ID | tag::BOOL::ID => Some(1),
+        tag::U16::ID => Some(2),
+        tag::U32::ID => Some(4),
+        tag::U64::ID => Some(8),
+        tag::U128::ID => Some(16),
+        tag::BUF32::ID => Some(32),
+        _ => None
+    }
+}
+
+fn take_n<'a>(buf: &mut &'a [u8], n: usize) -> R<&'a [u8]> {
+    if n > buf.len() {
+        return Err("data_convert: unexpected end".into());
+    }
+    let (a, rest) = buf.split_at(n);
+    *buf = rest;
+    Ok(a)
+}
+
+fn take_u16(buf: Buf) -> R<u16> {
+    let b = take_n(buf, 2)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+// Item schemas and count, after the TUPLE tag
+fn take_tuple<'a>(schema: &mut &'a [u8]) -> R<(&'a [u8], u8)> {
+    let n = take_n(schema, 1)?[0];
+    let len = take_u16(schema)?;
+    Ok((take_n(schema, len as usize)?, n))
+}
 
+fn skip_schema(schema: Buf) {
+    let mut p = SchemaParser(ParserBuffer::new(schema));
+    p.skip_schema(1);
+    *schema = &schema[schema.len() - p.0.len()..];
+}
 
 
 
@@ -120,7 +324,12 @@ impl SchemaParser {
                 tag::BUFFER::ID => {},
                 tag::BUF32::ID => {},
                 tag::LIST::ID => self.skip_schema(1),
-                tag::RSTRUCT::ID => {},
+                tag::RSTRUCT::ID => {
+                    // skip size, then the fields
+                    self.skip_bytes(2);
+                    let n = self.next();
+                    self.skip_schema(n as usize);
+                },
                 tag::TUPLE::ID => {
                     // skip vec len
                     self.skip_bytes(1);
@@ -166,8 +375,65 @@ mod tests {
         <(bool, u16)>::encode_schema(out);
         assert!(*out == vec![tag::TUPLE::ID, 2, 2, 0, tag::BOOL::ID, tag::U16::ID]);
     }
-}
 
+    #[test]
+    fn test_superset() {
+        fn sup<A: SchemaType, B: SchemaType>() -> bool {
+            let (a, b) = (A::to_schema(), B::to_schema());
+            schema_is_superset(&mut a.parser(), &mut b.parser())
+        }
+        assert!(sup::<u8, u8>());
+        assert!(!sup::<u8, u16>());
+        assert!(sup::<(u8, String), (u8, String, Vec<u16>)>());
+        assert!(sup::<Vec<(u8, bool)>, Vec<(u8, bool, u64)>>());
+        assert!(!sup::<(u8, String, u8), (u8, String)>());
+        assert!(!sup::<(u8, String), (u16, String, u8)>());
+    }
+
+    #[test]
+    fn test_data_convert() {
+        type Old = Vec<(u8, Option<String>)>;
+        type New = Vec<(u8, Option<String>, (u64, Vec<u8>))>;
+        let old: Old = vec![(1, Some("a".into())), (2, None)];
+        let new = data_convert(&Old::to_schema(), &New::to_schema(), &old.rd_encode()).unwrap();
+        let expected: New = vec![(1, Some("a".into()), (0, vec![])), (2, None, (0, vec![]))];
+        assert_eq!(new, expected.rd_encode());
+
+        let back = data_convert(&New::to_schema(), &Old::to_schema(), &new).unwrap();
+        assert_eq!(back, old.rd_encode());
+        assert!(data_convert(&Old::to_schema(), &u8::to_schema(), &old.rd_encode()).is_err());
+        assert!(data_convert(&Old::to_schema(), &New::to_schema(), &old.rd_encode()[..5]).is_err());
+    }
 
+    #[test]
+    fn test_tuple_parse() {
+        use super::super::encode::Encode;
+        let mut schema = vec![];
+        <(Vec<u8>, u16, String)>::encode_schema(&mut schema);
+        let rd: RD = (vec![1u8, 2], 3u16, "a".to_string()).into();
+        let mut buf = (schema.len() as u16).rd_encode();
+        buf.extend(schema);
+        buf.extend(rd.rd_encode());
+        assert_eq!(data_parse(&mut ParserBuffer::new(&buf)).unwrap(), rd);
+    }
 
+    #[test]
+    fn test_data_matches() {
+        let rd: RD = (vec![Some(1u8), None], 3u16, "a".to_string()).into();
+        assert!(data_matches(&<(Vec<Option<u8>>, u16, String)>::to_schema(), &rd));
+        assert!(!data_matches(&<(Vec<Option<u16>>, u16, String)>::to_schema(), &rd));
+        assert!(!data_matches(&<(Vec<Option<u8>>, u16)>::to_schema(), &rd));
+        assert!(!data_matches(&u8::to_schema(), &rd));
+        assert!(data_matches(&Vec::<u64>::to_schema(), &Vec::<u64>::new().into()));
+        assert!(!data_matches(&Vec::<u8>::to_schema(), &vec![1u64].into()));
+    }
 
+    #[test]
+    fn test_schema_has_function() {
+        use crate::vm::expr::Function;
+        assert!(!schema_has_function(&<(Vec<Option<u8>>, u16, String)>::to_schema()));
+        assert!(schema_has_function(&Function::<u8, u8>::to_schema()));
+        assert!(schema_has_function(&<(u8, Vec<Function<u8, u8>>)>::to_schema()));
+        assert!(schema_has_function(&<(u8, (String, Option<Function<(), u8>>), u16)>::to_schema()));
+    }
+}
@@ -69,6 +69,61 @@ macro_rules! tuple_types {
 tuple_types!(A, B, C, D, E, F, G);
 
 
+/*
+ * Schema as Rust-like text, ie "Function<(u8, String), Option<u64>>"
+ */
+impl std::fmt::Display for Schema {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_schema(&mut self.0.as_slice(), f)
+    }
+}
+
+fn fmt_schema(buf: &mut &[u8], f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let Some((&t, rest)) = buf.split_first() else {
+        return write!(f, "?");
+    };
+    *buf = rest;
+    let name = match t {
+        tag::UNIT::ID => "()",
+        tag::U8::ID => "u8",
+        tag::U16::ID => "u16",
+        tag::U32::ID => "u32",
+        tag::U64::ID => "u64",
+        tag::U128::ID => "u128",
+        tag::BOOL::ID => "bool",
+        tag::STRING::ID => "String",
+        tag::BUFFER::ID => "Buffer",
+        tag::BUF32::ID => "[u8; 32]",
+        tag::OPTION::ID | tag::LIST::ID => {
+            write!(f, "{}<", if t == tag::OPTION::ID { "Option" } else { "Vec" })?;
+            fmt_schema(buf, f)?;
+            return write!(f, ">");
+        },
+        tag::FUNCTION::ID => {
+            write!(f, "Function<")?;
+            fmt_schema(buf, f)?;
+            write!(f, ", ")?;
+            fmt_schema(buf, f)?;
+            return write!(f, ">");
+        },
+        tag::TUPLE::ID if buf.len() >= 3 => {
+            let n = buf[0];
+            *buf = &buf[3..];
+            write!(f, "(")?;
+            for i in 0..n {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                fmt_schema(buf, f)?;
+            }
+            return write!(f, ")");
+        },
+        t => return write!(f, "<tag {}>", t),
+    };
+    write!(f, "{}", name)
+}
+
+
 
 
 
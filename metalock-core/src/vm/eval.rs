@@ -1,19 +1,36 @@
 
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
 use std::marker::PhantomData;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use solana_program::{account_info::AccountInfo, pubkey::Pubkey, program_error::ProgramError};
+use solana_program::sysvar::{Sysvar, clock::Clock, rent::Rent};
 
 #[cfg(feature = "measure-cu")]
-use anchor_lang::solana_program::compute_units::sol_remaining_compute_units;
+use solana_program::compute_units::sol_remaining_compute_units;
 
 #[cfg(feature = "anchor")]
-use anchor_lang::{prelude::*, solana_program as sp};
+use solana_program::instruction::{AccountMeta, Instruction};
+#[cfg(feature = "anchor")]
+use crate::types::native::*;
+#[cfg(feature = "anchor")]
+use super::invoke::*;
 
 use crate::types::tlist::*;
 use crate::types::core::*;
 use crate::types::decode::*;
+use crate::types::encode::Encode;
 use crate::types::data::*;
 use crate::types::newval::*;
 use crate::types::parse::*;
 use super::expr::*;
+use super::host::*;
+use super::sig::*;
+use super::verify::*;
+use super::header::Header;
+use super::resource::ResourceStore;
 
 pub use super::expr::Function;
 
@@ -23,13 +40,13 @@ macro_rules! orsome {
     ($self:ident, $p:ident, |$a:ident| $ae:expr, |$res:ident| $rese:expr) => {
         {
             let p = EvalParser::from($self, $p);
-            let C($a, p) = p.eval();
+            let C($a, p) = p.eval()?;
             if let RD::Option(r) = $ae {
                 if let Some($res) = r.as_ref() {
-                    p.skip();
+                    p.skip()?;
                     $rese
                 } else {
-                    p.eval()
+                    p.eval()?
                 }
             } else {
                 panic!("expected RD::Option")
@@ -42,40 +59,153 @@ macro_rules! fn_map {
     (|$self:ident, $p:ident, $f:ident, $val:ident| $expr:expr) => {
         {
             let p = EvalParser::from($self, $p);
-            let ($val, EncodedFunction(ref_id, body)) = p.eval().take_fun1();
+            let ($val, fun) = p.eval()?.take_fun1()?;
+            let EncodedFunction(ref_id, body) = fun;
+            // Crossing between the program's and foreign functions swaps vars
+            let foreign = $self.is_foreign(fun) != $self.in_foreign;
 
             let buf = *$self.buf;
+            let code = $self.code;
+            let saved = $self.vars[*ref_id as usize].clone();
 
             let mut $f = |item: &RD| {
+                if foreign {
+                    return $self.call(fun, item.clone());
+                }
                 $self.vars[*ref_id as usize] = item.clone();
                 *$self.buf = body;
+                $self.code = body;
                 $self.eval()
             };
+            // On error the state is restored by TRY, see EvalError
 
             let out = $expr;
             *$self.buf = buf;
+            $self.code = code;
+            $self.vars[*ref_id as usize] = saved;
             out
         }
     };
 }
 
 
+macro_rules! failed {
+    ($($arg:tt)*) => { EvalError::Failed(format!($($arg)*)) };
+}
+macro_rules! fail {
+    ($($arg:tt)*) => { return Err(failed!($($arg)*)) };
+}
+
+
+/*
+ * Failures that depend on runtime values are returned as EvalErrors, so they
+ * can be recovered with TRY, or returned to the host from try_run without
+ * unwinding (which the SVM can't do). Type errors, which a program built with
+ * the typed DSL can't produce, still panic.
+ *
+ * Ops don't restore evaluator state when returning an error; TRY restores
+ * its own snapshot.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EvalError {
+    // PANIC, ASSERT, out of range, overflow, etc. Recoverable with TRY.
+    Failed(String),
+    // ASSERT_CODE, also recoverable
+    Code(u32),
+    // Cost limit and call depth, these always abort the evaluation
+    Limit(String),
+    // Malformed or newer bytecode: (byte, offset in the program or function body)
+    InvalidOpcode(u8, usize),
+    // Refused by Evaluator::load, see Header
+    Unsupported(String),
+}
+
+impl EvalError {
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, EvalError::Failed(_) | EvalError::Code(_))
+    }
+
+    // For the host to map to its own errors, ie ProgramError::Custom
+    pub fn code(&self) -> Option<u32> {
+        match self {
+            EvalError::Code(code) => Some(*code),
+            _ => None
+        }
+    }
+}
+
+impl std::fmt::Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::Failed(s) | EvalError::Limit(s) => write!(f, "{}", s),
+            EvalError::Code(code) => write!(f, "ASSERT_CODE: {}", code),
+            EvalError::InvalidOpcode(byte, offset) => write!(f, "Invalid opcode: 0x{:02x} at {}", byte, offset),
+            EvalError::Unsupported(s) => write!(f, "Unsupported program: {}", s),
+        }
+    }
+}
+
+
 #[derive(Default, Clone)]
 pub struct EvaluatorContext {
-    //#[cfg(feature = "anchor")]
-    //pub proxy_calls: Vec<MetalockProxyCall>,
-    //#[cfg(feature = "anchor")]
-    //pub remaining_accounts: &'static [AccountInfo<'static>],
-    //pub beastie_seeds: Vec<&'static [u8]>
+    pub host_functions: HostFunctions,
+    // Accounts that programs can read, see ACCOUNT
+    pub accounts: &'static [AccountInfo<'static>],
+    pub clock: Clock,
+    pub rent: Rent,
+    // Passed to INVOKE_SIGNED
+    #[cfg(feature = "anchor")]
+    pub remaining_accounts: &'static [AccountInfo<'static>],
+    #[cfg(feature = "anchor")]
+    pub signer_seeds: Vec<&'static [u8]>,
+    // None allows any program to be invoked
+    #[cfg(feature = "anchor")]
+    pub invoke_allowlist: Option<Vec<Pubkey>>,
+    // None uses SolanaInvoker
+    #[cfg(feature = "anchor")]
+    pub invoker: Option<&'static dyn Invoker>,
+    // Maximum cost units for a run, None is unlimited. See Evaluator::charge
+    pub cost_limit: Option<u64>,
+    // None is DEFAULT_MAX_CALL_DEPTH
+    pub max_call_depth: Option<u16>,
+    // For RESOURCE_GET and RESOURCE_SET
+    pub resources: Option<Rc<RefCell<dyn ResourceStore>>>,
+    // Whether RESOURCE_SET is allowed
+    pub resources_writable: bool,
+}
+
+// SBF allows 64 call frames, and each CALL takes at least 4 Rust frames
+// (call, fetch, eval, eval of the body), more when the call is nested in
+// an expression. That's measured on a release build; 8 calls leave room
+// for the entrypoint and the expressions in between. Raise it off chain.
+pub const DEFAULT_MAX_CALL_DEPTH: u16 = 8;
+pub const MAX_VARS: usize = 100;
+
+impl EvaluatorContext {
+    // On chain, populate clock and rent from the sysvars
+    pub fn load_sysvars(&mut self) -> Result<(), ProgramError> {
+        self.clock = Clock::get()?;
+        self.rent = Rent::get()?;
+        Ok(())
+    }
 }
 
 
 pub struct Evaluator {
     start: ParserBuffer,
+    // The program or function body being evaluated, for error offsets
+    code: &'static [u8],
     pub(crate) buf: ParserBuffer,
     vars: Vec<RD>, // Vector of pointers
     ctx: EvaluatorContext,
-    stack: Vec<u8>,
+    cost: u64,
+    call_depth: u16,
+    // Functions defined by the running program, see check_function
+    own_functions: BTreeSet<usize>,
+    verified_functions: BTreeSet<usize>,
+    in_foreign: bool,
+    // The program's vars, set aside while foreign functions run
+    own_vars: Vec<Vec<RD>>,
     #[cfg(feature = "measure-cu")]
     profile: (OP, u64, BTreeMap<OP, u64>),
 }
@@ -84,22 +214,49 @@ impl Evaluator {
     pub fn new<'a, 'b>(buf: Buf<'a, 'b>, ctx: EvaluatorContext) -> Evaluator {
         Evaluator {
             start: ParserBuffer::new(*buf),
+            code: ParserBuffer::new(buf).0,
             buf: ParserBuffer::new(*buf),
-            vars: vec![RD::Unit(); 100],
+            vars: vec![RD::Unit(); MAX_VARS],
             ctx,
-            stack: vec![0],
+            cost: 0,
+            call_depth: 0,
+            own_functions: Default::default(),
+            verified_functions: Default::default(),
+            in_foreign: false,
+            own_vars: vec![],
             #[cfg(feature = "measure-cu")]
             profile: Default::default(),
         }
     }
 
+    // Code with a header, as compiled by Program::compile
+    pub fn load<'a, 'b>(buf: Buf<'a, 'b>, ctx: EvaluatorContext) -> Result<Evaluator, EvalError> {
+        let header = Header::decode(buf).map_err(EvalError::Unsupported)?;
+        header.check().map_err(EvalError::Unsupported)?;
+        let info = header.verify(buf).map_err(EvalError::Unsupported)?;
+        for (id, signature) in &info.host_calls {
+            ctx.host_functions.check(*id, signature).map_err(EvalError::Unsupported)?;
+        }
+        Ok(Evaluator::new(buf, ctx))
+    }
+
     pub fn run(&mut self, input: RD) -> RD {
+        self.try_run(input).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_run(&mut self, input: RD) -> Result<RD, EvalError> {
         self.vars[0] = input;
         self.eval()
     }
 
-    fn eval(&mut self) -> RD {
-        let op = self.take_op();
+    fn eval(&mut self) -> Result<RD, EvalError> {
+        let op = self.take_op()?;
+        self.charge(1)?;
+        // Verified function values have no privileged opcodes, and the
+        // program's own functions can't use them when a foreign one calls them
+        if !self.own_vars.is_empty() && op.is_privileged() {
+            fail!("opcode 0x{:02x} not allowed in a function value", Into::<u8>::into(op));
+        }
 
         #[cfg(feature = "measure-cu")]
         let last_op = {
@@ -118,34 +275,39 @@ impl Evaluator {
 
             OP::AND(p) => {
                 let p = EvalParser::from(self, p);
-                let C(r, p) = p.eval();
+                let C(r, p) = p.eval()?;
                 if r._as() {
-                    p.eval()
+                    p.eval()?
                 } else {
-                    p.skip();
+                    p.skip()?;
                     false.into()
                 }
             },
 
             OP::OR(p) => {
                 let p = EvalParser::from(self, p);
-                let C(r, p) = p.eval();
+                let C(r, p) = p.eval()?;
                 if r._as() {
-                    p.skip();
+                    p.skip()?;
                     r
                 } else {
-                    p.eval()
+                    p.eval()?
                 }
             },
-            OP::NOT(_) => RD::Bool(self.eval()._as::<bool>() == false),
+            OP::NOT(_) => RD::Bool(self.eval()?._as::<bool>() == false),
 
             OP::EQ(p) => {
                 let p = EvalParser::from(self, p);
-                let (a, b) = p.eval().eval();
+                let (a, b) = p.eval()?.eval()?;
                 (a == b).into()
             },
+            OP::LT(p) => {
+                let p = EvalParser::from(self, p);
+                let (a, b) = p.eval()?.eval()?;
+                (total_cmp(&a, &b, "LT")? == Ordering::Less).into()
+            },
             OP::LEN(_) => {
-                ((match self.eval() {
+                ((match self.eval()? {
                     RD::String(s) => s.len(),
                     RD::List(s) => s.len(),
                     RD::Buffer(s) => s.len(),
@@ -153,161 +315,402 @@ impl Evaluator {
                 }) as u16).into()
             },
             OP::ADD(_) => {
-                match (self.eval(), self.eval()) {
-                    (RD::U8(a),  RD::U8(b))  => (a+b).into(),
-                    (RD::U16(a), RD::U16(b)) => (a+b).into(),
-                    (RD::U32(a), RD::U32(b)) => (a+b).into(),
-                    (RD::U64(a), RD::U64(b)) => (*a+*b).into(),
-                    (a, b) => panic!("OP::ADD unexpected: {:?}, {:?}", a, b)
-                }
+                let (a, b) = (self.eval()?, self.eval()?);
+                checked_add(&a, &b).ok_or_else(|| failed!("ADD: overflow: {:?} + {:?}", a, b))?
+            },
+            OP::SUB(_) => {
+                let (a, b) = (self.eval()?, self.eval()?);
+                checked_sub(&a, &b).ok_or_else(|| failed!("SUB: overflow: {:?} - {:?}", a, b))?
+            },
+            OP::MUL(_) => {
+                let (a, b) = (self.eval()?, self.eval()?);
+                checked_mul(&a, &b).ok_or_else(|| failed!("MUL: overflow: {:?} * {:?}", a, b))?
             },
 
             //
-            OP::SEQ(_) => { self.eval(); self.eval() },
+            OP::SEQ(_) => { self.eval()?; self.eval()? },
 
             //
-            OP::MAP(p) => {
-                fn_map!(|self, p, f, val| {
-                    match val {
-                        RD::List(v) => v.iter().map(f).collect::<Vec<_>>().into(),
-                        RD::Option(o) => o.as_ref().map(f).into(),
-                        RD::Native(c) => c.iter().map(|o| f(&o)).collect::<Vec<_>>().into(),
-                        _ => panic!("OP::MAP: unexpected")
-                    }
-                })
-            },
-            OP::ALL(p) => self.fn_map(p).into_iter().all(|rd| rd == RD::Bool(true)).into(),
-            OP::ANY(p) => self.fn_map(p).iter().any(|rd| rd == &RD::Bool(true)).into(),
-            OP::EACH(p) => { self.fn_map(p); RD::Unit() },
-            OP::SLICE(_) => {
-                let o = self.eval();
-                let idx = self.eval()._as::<u16>() as usize;
-                match o {
-                    RD::List(vec) => vec[idx..].to_vec().into(),
-                    RD::Native(c) => c.slice(idx).into(),
-                    _ => panic!("SLICE: Expecting RD::List")
-                }
-            },
-            OP::INDEX(p) => {
-                let p = EvalParser::from(self, p);
-                let C(rd, p) = p.eval();
-                let idx = p.eval_as::<u16>() as usize;
-
-                match rd {
-                    RD::List(vec) => vec[idx].clone(),
-                    RD::Tuple(vec) => vec[idx].clone(),
-                    RD::Native(p) => (*p).index(idx).into(),
-                    _ => panic!("INDEX: Expecting RD::[List,Tuple,Native], got: {:?}", rd)
-                }
-            },
+            OP::MAP(_) | OP::ALL(_) | OP::ANY(_) | OP::EACH(_) | OP::FILTER(_) | OP::FOLD(_) |
+            OP::REDUCE(_) | OP::FIND(_) | OP::POSITION(_) | OP::SUM(_) | OP::COUNT(_) | OP::ZIP(_) |
+            OP::ENUMERATE(_) | OP::RANGE(_) | OP::LIST_CONCAT(_) | OP::PUSH(_) | OP::REVERSE(_) | OP::TAKE(_) |
+            OP::SORT(_) | OP::SORT_BY_KEY(_) | OP::DEDUP(_) | OP::IS_SORTED(_) | OP::SLICE(_) | OP::INDEX(_) => self.eval_list(op.clone())?,
 
             //
             OP::VAL(p) => {
-                data_parse(&mut self.buf).expect("failed reading data")
+                let rd = data_parse(&mut self.buf).expect("failed reading data");
+                if let (RD::Function(_), false) = (&rd, self.in_foreign) {
+                    self.own_functions.insert(rd._as::<&EncodedFunction>() as *const _ as usize);
+                }
+                rd
             },
             OP::VAR(p) => {
-                let var_id = EvalParser::from(self, p).take();
+                let var_id = EvalParser::from(self, p).take()?;
                 self.vars[*var_id as usize].clone()
             },
             OP::SETVAR(p) => {
                 let p = EvalParser::from(self, p);
-                let (ref_id, r) = p.take().eval();
+                let (ref_id, r) = p.take()?.eval()?;
                 self.vars[*ref_id as usize] = r;
                 RD::Unit()
             },
+            OP::LET(_) => {
+                let var_id = self.buf.take_u16() as usize;
+                let value = self.eval()?;
+                let saved = std::mem::replace(&mut self.vars[var_id], value);
+                let out = self.eval()?;
+                self.vars[var_id] = saved;
+                out
+            },
 
             OP::GET_STRUCT_FIELD(p) => {
                 let p = EvalParser::from(self, p);
-                let ((c, field), off) = p.eval_as::<&'static Native>().take().take();
-                c.get_struct_field(field, off)
+                let ((c, field), off) = p.eval_as::<&'static Native>()?.take()?.take()?;
+                c.get_struct_field(field, off)?
             },
 
             OP::SET_STRUCT_FIELD(p) => {
                 let p = EvalParser::from(self, p);
-                let (((c, _), off), val) = p.eval_as::<&'static Native>().take().take().eval();
+                let (((c, _), off), val) = p.eval_as::<&'static Native>()?.take()?.take()?.eval()?;
                 c.set_struct_field(off, val).into()
             },
 
             OP::IF(p) => {
                 let p = EvalParser::from(self, p);
-                let C(e, p) = p.eval();
+                let C(e, p) = p.eval()?;
 
                 if e._as() {
-                    p.eval().skip().0
+                    p.eval()?.skip()?.0
                 } else {
-                    p.skip().eval().1
+                    p.skip()?.eval()?.1
+                }
+            },
+
+            OP::REPEAT(p) => {
+                let p = EvalParser::from(self, p);
+                let ((n, init), f) = p.eval_as::<u16>()?.eval()?.take_fun1()?;
+                (0..n).try_fold(init, |acc, i| {
+                    self.charge(1)?;
+                    self.call(f, (i, acc).into())
+                })?
+            },
+            OP::WHILE(p) => {
+                let p = EvalParser::from(self, p);
+                let (((max, mut acc), cond), body) = p.eval_as::<u16>()?.eval()?.take_fun1()?.take_fun1()?;
+                let mut i = 0;
+                while self.call(cond, acc.clone())?._as::<bool>() {
+                    if i >= max {
+                        fail!("WHILE: exceeded {} iterations", max);
+                    }
+                    self.charge(1)?;
+                    acc = self.call(body, acc)?;
+                    i += 1;
+                }
+                acc
+            },
+
+            /*
+             * TRY evaluates its body, and yields None if it fails. The body is
+             * length prefixed so evaluation can resume after it, and vars and
+             * frames are restored from a snapshot, so var writes in a failed
+             * body are rolled back. Effects outside the evaluator are not:
+             * RESOURCE_SET writes, INVOKE_SIGNED calls and host calls made
+             * before the failure stay done. Limits are not recoverable.
+             */
+            OP::TRY(_) => {
+                let len = self.buf.take_u16() as usize;
+                let end = &self.buf[len..];
+                let snapshot = (self.vars.clone(), self.own_vars.clone(), self.call_depth, self.in_foreign, self.code);
+                match self.eval() {
+                    Ok(r) => Some(r).into(),
+                    Err(e) if e.is_recoverable() => {
+                        (self.vars, self.own_vars, self.call_depth, self.in_foreign, self.code) = snapshot;
+                        *self.buf = end;
+                        None::<RD>.into()
+                    },
+                    Err(e) => return Err(e)
                 }
             },
 
-            OP::TO_SOME(_) => { Some(self.eval()).into() },
+            OP::TO_SOME(_) => { Some(self.eval()?).into() },
             OP::FROM_SOME(p) => { orsome!(self, p, |a| a, |res| res.clone()) },
             OP::OR_SOME(p) => orsome!(self, p, |a| &a, |_res| a),
 
-            //#[cfg(feature = "anchor")]
-            //OP::INVOKE_SIGNED(_) => {
-            //    let call = match self.eval() {
-            //        RD::Native(c) => {
-            //            let Native(_rs, p) = &*c;
-            //            unsafe { &*(*p as *const MetalockProxyCall) }
-            //        },
-            //        _ => panic!("INVOKE_SIGNED: expected Native")
-            //    };
-
-            //    // Not as much of a CU saving as you might think
-            //    let accounts: Vec<AccountMeta> = unsafe {
-            //        let p: &[usize; 3] = std::mem::transmute(&call.accounts);
-            //        std::mem::transmute(*p)
-            //    };
-
-            //    //let cu = sol_remaining_typed parser that yields bcompute_units();
-            //    sp::program::invoke_signed_unchecked(
-            //        &sp::instruction::Instruction::new_with_bytes(call.program_id, &call.data.0, accounts),
-            //        self.ctx.remaining_accounts,
-            //        &[&*self.ctx.beastie_seeds],
-            //    ).expect("INVOKE_SIGNED: call failed");
-            //    //msg!("console.log call used: {}", cu - sol_remaining_compute_units());
-
-            //    RD::Unit()
-            //},
-            //#[cfg(feature = "anchor")]
-            //OP::GET_INVOKE_RETURN(_) => {
-            //    let (_, r) = sp::program::get_return_data().expect("Expected instruction to return data");
-            //    Buffer(r).into()
-            //},
-
-            OP::PANIC(_) => {
-                let s: &String = self.eval()._as();
-                panic!("{}", s);
+            #[cfg(feature = "anchor")]
+            OP::PROXY_CALL(p) => {
+                let p = EvalParser::from(self, p);
+                let ((program_id, data), accounts) = p.eval()?.eval()?.eval()?;
+                let accounts = match accounts {
+                    RD::List(v) => v.iter().map(|a| a._as::<&'static Native>().deref::<AccountMeta>().clone()).collect(),
+                    RD::Native(c) => c.iter().map(|a| a._as::<&'static Native>().deref::<AccountMeta>().clone()).collect(),
+                    _ => panic!("PROXY_CALL: expected accounts list")
+                };
+                MetalockProxyCall {
+                    program_id: Pubkey::from(*program_id._as::<&'static [u8; 32]>()),
+                    data: data._as::<&'static Buffer>().clone(),
+                    accounts
+                }.into()
+            },
+            #[cfg(feature = "anchor")]
+            OP::INVOKE_SIGNED(_) => {
+                let call = self.eval()?._as::<&'static Native>().deref::<MetalockProxyCall>();
+
+                if let Some(allowlist) = &self.ctx.invoke_allowlist {
+                    if !allowlist.contains(&call.program_id) {
+                        fail!("INVOKE_SIGNED: program not allowed: {}", call.program_id);
+                    }
+                }
+
+                let ix = Instruction::new_with_bytes(call.program_id, &call.data.0, call.accounts.clone());
+                self.invoker().invoke_signed(
+                    &ix,
+                    self.ctx.remaining_accounts,
+                    &[&*self.ctx.signer_seeds],
+                ).map_err(|e| failed!("INVOKE_SIGNED: call failed: {}", e))?;
+
+                RD::Unit()
+            },
+            #[cfg(feature = "anchor")]
+            OP::GET_INVOKE_RETURN(_) => {
+                let (_, r) = self.invoker().get_return_data().ok_or_else(|| failed!("Expected instruction to return data"))?;
+                Buffer(r).into()
+            },
+
+            OP::ACCOUNT(p) => {
+                let p = EvalParser::from(self, p);
+                let (idx, field) = p.eval_as::<u16>()?.take()?;
+                let acc = self.account(idx)?;
+                match field {
+                    account_field::KEY => (acc.key as *const Pubkey as *const [u8; 32]).into(),
+                    account_field::OWNER => (acc.owner as *const Pubkey as *const [u8; 32]).into(),
+                    account_field::LAMPORTS => acc.lamports().into(),
+                    account_field::IS_SIGNER => acc.is_signer.into(),
+                    account_field::IS_WRITABLE => acc.is_writable.into(),
+                    account_field::DATA => Buffer(acc.data.borrow().to_vec()).into(),
+                    o => fail!("ACCOUNT: invalid field: {}", o)
+                }
+            },
+            OP::ACCOUNT_NATIVE(_) => {
+                let schema = Schema(self.buf.decode::<Buffer>().0);
+                let offset = self.buf.take_u16() as usize;
+                let idx = self.eval()?._as::<u16>();
+                let acc = self.account(idx)?;
+                let size = Native::pod_size(&schema).ok_or_else(|| failed!("ACCOUNT_NATIVE: not a plain struct"))?;
+                let data = acc.data.borrow();
+                if offset + size > data.len() {
+                    fail!("ACCOUNT_NATIVE: account {} data too short: {} < {}", idx, data.len(), offset + size);
+                }
+                Native(schema, unsafe { data.as_ptr().add(offset) }).into()
+            },
+
+            OP::RESOURCE_GET(_) => {
+                let schema = self.buf.decode::<Buffer>().0;
+                let name = self.eval()?;
+                let data = self.resources("RESOURCE_GET")?.borrow().get(&Schema(schema.clone()), name._as::<&String>());
+                match data {
+                    Some(data) => {
+                        let buf = [(schema.len() as u16).rd_encode(), schema, data].concat();
+                        let rd = data_parse(&mut ParserBuffer::new(&buf));
+                        Some(rd.map_err(|e| failed!("RESOURCE_GET: {}", e))?)
+                    },
+                    None => None
+                }.into()
+            },
+            OP::RESOURCE_SET(_) => {
+                let schema = Schema(self.buf.decode::<Buffer>().0);
+                let name = self.eval()?;
+                let value = self.eval()?;
+                if !self.ctx.resources_writable {
+                    fail!("RESOURCE_SET: not allowed");
+                }
+                self.resources("RESOURCE_SET")?.borrow_mut().set(&schema, name._as::<&String>(), value.rd_encode())
+                    .map_err(|e| failed!("RESOURCE_SET: {}", e))?;
+                RD::Unit()
+            },
+
+            OP::CLOCK(p) => {
+                let field = EvalParser::from(self, p).take()?;
+                let clock = &self.ctx.clock;
+                match field {
+                    clock_field::SLOT => clock.slot.into(),
+                    clock_field::EPOCH => clock.epoch.into(),
+                    clock_field::UNIX_TIMESTAMP => (clock.unix_timestamp.max(0) as u64).into(),
+                    o => fail!("CLOCK: invalid field: {}", o)
+                }
+            },
+            OP::RENT_MINIMUM_BALANCE(_) => {
+                let data_len = self.eval()?._as::<u64>();
+                self.ctx.rent.minimum_balance(data_len as usize).into()
+            },
+
+            OP::CREATE_PROGRAM_ADDRESS(_) => {
+                let (seeds, program_id) = self.eval_seeds()?;
+                Pubkey::create_program_address(&seeds, &program_id).ok().into()
+            },
+            OP::FIND_PROGRAM_ADDRESS(_) => {
+                let (seeds, program_id) = self.eval_seeds()?;
+                Pubkey::find_program_address(&seeds, &program_id).into()
+            },
+
+            OP::HOSTCALL(_) => {
+                let id = self.buf.take_u16();
+                let len = self.buf.take_u16() as usize;
+                let signature = Schema(self.buf.0[..len].to_vec());
+                self.buf.skip_bytes(len);
+                let nargs = self.buf.next();
+                let args = (0..nargs).map(|_| self.eval()).collect::<Result<Vec<_>, _>>()?;
+                let host_functions = &self.ctx.host_functions;
+                let hf = host_functions.get(id).ok_or_else(|| failed!("HOSTCALL: unknown function: {}", id))?;
+                host_functions.check(id, &signature).map_err(EvalError::Failed)?;
+                if hf.input.len() != args.len() {
+                    fail!("HOSTCALL {}: expected {} args, got {}", id, hf.input.len(), args.len());
+                }
+                if let Some(i) = (0..args.len()).find(|i| !data_matches(&hf.input[*i], &args[*i])) {
+                    fail!("HOSTCALL {}: arg {} is not a {}", id, i, hf.input[i]);
+                }
+                let out = (hf.f)(&args).map_err(|e| failed!("HOSTCALL {}: {}", id, e))?;
+                if !data_matches(&hf.output, &out) {
+                    fail!("HOSTCALL {}: result is not a {}", id, hf.output);
+                }
+                out
+            },
+
+            OP::SHA256(_) => solana_program::hash::hash(self.eval_bytes("SHA256")?).to_bytes().into(),
+            OP::KECCAK256(_) => solana_program::keccak::hash(self.eval_bytes("KECCAK256")?).to_bytes().into(),
+            OP::BLAKE3(_) => solana_program::blake3::hash(self.eval_bytes("BLAKE3")?).to_bytes().into(),
+            OP::ED25519_VERIFY(_) => {
+                let pubkey = Pubkey::new_from_array(*self.eval()?._as::<&'static [u8; 32]>());
+                let message = self.eval_bytes("ED25519_VERIFY")?;
+                let signature = self.eval_bytes("ED25519_VERIFY")?;
+                ed25519_verify(self.ctx.accounts, &pubkey, message, signature).into()
+            },
+            OP::SECP256K1_RECOVER(_) => {
+                let hash = self.eval_bytes("SECP256K1_RECOVER")?;
+                let recovery_id = self.eval()?._as::<u8>();
+                let signature = self.eval_bytes("SECP256K1_RECOVER")?;
+                secp256k1_recover(hash, recovery_id, signature).map(|pk| Buffer(pk.to_vec())).into()
+            },
+            OP::CONCAT(_) => {
+                let a = self.eval_bytes("CONCAT")?;
+                let b = self.eval_bytes("CONCAT")?;
+                Buffer([a, b].concat()).into()
+            },
+            OP::STR_CONCAT(_) => {
+                let a = self.eval()?;
+                let b = self.eval()?;
+                (a._as::<&'static String>().clone() + b._as::<&'static String>()).into()
+            },
+            OP::BYTES_SLICE(_) => {
+                let rd = self.eval()?;
+                let start = self.eval()?._as::<u16>() as usize;
+                let end = self.eval()?._as::<u16>() as usize;
+                match rd {
+                    RD::Buffer(b) => {
+                        let s = b.0.get(start..end).ok_or_else(|| {
+                            failed!("BYTES_SLICE: range {}..{} out of bounds ({})", start, end, b.0.len())
+                        })?;
+                        Buffer(s.to_vec()).into()
+                    },
+                    RD::String(s) => {
+                        let s = s.get(start..end).ok_or_else(|| {
+                            failed!("BYTES_SLICE: range {}..{} out of bounds or not on char boundary ({:?})", start, end, *s)
+                        })?;
+                        s.into()
+                    },
+                    o => panic!("BYTES_SLICE: expected String or Buffer, got: {:?}", o)
+                }
+            },
+            OP::STARTS_WITH(_) => {
+                let a = self.eval_bytes("STARTS_WITH")?;
+                a.starts_with(self.eval_bytes("STARTS_WITH")?).into()
+            },
+            OP::ENDS_WITH(_) => {
+                let a = self.eval_bytes("ENDS_WITH")?;
+                a.ends_with(self.eval_bytes("ENDS_WITH")?).into()
+            },
+            OP::CONTAINS(_) => {
+                let a = self.eval_bytes("CONTAINS")?;
+                let b = self.eval_bytes("CONTAINS")?;
+                (b.is_empty() || a.windows(b.len()).any(|w| w == b)).into()
+            },
+            OP::BYTE_AT(_) => {
+                let a = self.eval_bytes("BYTE_AT")?;
+                let idx = self.eval()?._as::<u16>() as usize;
+                *a.get(idx).ok_or_else(|| failed!("BYTE_AT: index {} out of bounds ({})", idx, a.len()))?
+            }.into(),
+            OP::TO_BUFFER(_) => Buffer(self.eval_bytes("TO_BUFFER")?.to_vec()).into(),
+            OP::UTF8_DECODE(_) => {
+                let b = self.eval_bytes("UTF8_DECODE")?;
+                std::str::from_utf8(b).ok().map(|s| s.to_string()).into()
+            },
+            OP::FROM_LE_BYTES(_) => {
+                let a = self.eval_bytes("FROM_LE_BYTES")?;
+                let off = self.eval()?._as::<u16>() as usize;
+                let size = self.buf.next() as usize;
+                let b = a.get(off..off+size).ok_or_else(|| {
+                    failed!("FROM_LE_BYTES: {} bytes at offset {} out of bounds ({})", size, off, a.len())
+                })?;
+                match size {
+                    1 => b[0].into(),
+                    2 => u16::from_le_bytes(b.try_into().unwrap()).into(),
+                    4 => u32::from_le_bytes(b.try_into().unwrap()).into(),
+                    8 => u64::from_le_bytes(b.try_into().unwrap()).into(),
+                    16 => u128::from_le_bytes(b.try_into().unwrap()).into(),
+                    o => fail!("FROM_LE_BYTES: invalid size: {}", o)
+                }
+            },
+            OP::TO_LE_BYTES(_) => {
+                Buffer(match self.eval()? {
+                    RD::U8(n) => vec![n],
+                    RD::U16(n) => n.to_le_bytes().to_vec(),
+                    RD::U32(n) => n.to_le_bytes().to_vec(),
+                    RD::U64(n) => n.to_le_bytes().to_vec(),
+                    RD::U128(n) => n.to_le_bytes().to_vec(),
+                    o => panic!("TO_LE_BYTES: expected integer, got: {:?}", o)
+                }).into()
+            },
+
+            OP::PANIC(p) => {
+                let s: String = EvalParser::from(self, p).take()?;
+                fail!("{}", s);
             },
             OP::ASSERT(_) => {
-                let pass = self.eval() == true.into();
+                let pass = self.eval()? == true.into();
                 let len = self.buf.decode();
                 if pass {
                     self.skip(len);
                 } else {
-                    let msg: &String = self.eval()._as();
-                    panic!("{}", msg);
+                    let msg: &String = self.eval()?._as();
+                    fail!("{}", msg);
+                }
+                RD::Unit()
+            },
+            OP::ASSERT_CODE(p) => {
+                let p = EvalParser::from(self, p);
+                let (pass, code) = p.eval_as::<bool>()?.take()?;
+                if !pass {
+                    return Err(EvalError::Code(code));
                 }
                 RD::Unit()
             },
 
             OP::CALL(p) => {
                 let p = EvalParser::from(self, p);
-                let C(input, p) = p.eval();
-                let f = p.take_fun1();
-
-                self.vars[f.0 as usize] = input;
-                self.fetch(&f.1)
+                let C(input, p) = p.eval()?;
+                let f = p.take_fun1()?;
+                self.call(f, input)?
             },
 
             OP::FETCH() => {
                 let off = self.buf.take_u16() as usize;
-                self.fetch(&self.start[off..])
+                let code = std::mem::replace(&mut self.code, self.start.0);
+                let out = self.fetch(&self.start[off..])?;
+                self.code = code;
+                out
             },
 
-            _ => {
-                panic!("Invalid opcode: {}", Into::<u8>::into(op))
-            },
+            // rejected by take_op
+            OP::NEVER(_) => unreachable!(),
         };
 
 
@@ -320,32 +723,353 @@ impl Evaluator {
             self.profile.1 = sol_remaining_compute_units();
         }
 
-        r
+        Ok(r)
     }
 
-    fn fetch(&mut self, buf: &'static [u8]) -> RD {
+    /*
+     * List ops, out of line so that eval's stack frame stays small for deep
+     * recursion
+     */
+    #[inline(never)]
+    fn eval_list(&mut self, op: OP) -> Result<RD, EvalError> {
+        Ok(match op {
+                OP::MAP(p) => {
+                    fn_map!(|self, p, f, val| {
+                        match val {
+                            RD::List(v) => v.iter().map(f).collect::<Result<Vec<_>, _>>()?.into(),
+                            RD::Option(o) => o.as_ref().map(f).transpose()?.into(),
+                            RD::Native(c) => c.iter().map(|o| f(&o)).collect::<Result<Vec<_>, _>>()?.into(),
+                            _ => panic!("OP::MAP: unexpected")
+                        }
+                    })
+                },
+                OP::ALL(p) => self.fn_map(p)?.into_iter().all(|rd| rd == RD::Bool(true)).into(),
+                OP::ANY(p) => self.fn_map(p)?.iter().any(|rd| rd == &RD::Bool(true)).into(),
+                OP::EACH(p) => { self.fn_map(p)?; RD::Unit() },
+
+                OP::FILTER(p) => {
+                    let p = EvalParser::from(self, p);
+                    let (list, f) = p.eval()?.take_fun1()?;
+                    let mut out = vec![];
+                    for item in list_items(list, "FILTER") {
+                        if self.call(f, item.clone())?._as::<bool>() {
+                            out.push(item);
+                        }
+                    }
+                    out.into()
+                },
+                OP::FOLD(p) => {
+                    let p = EvalParser::from(self, p);
+                    let ((list, init), f) = p.eval()?.eval()?.take_fun1()?;
+                    list_items(list, "FOLD").into_iter().try_fold(init, |acc, item| {
+                        self.call(f, (acc, item).into())
+                    })?
+                },
+                OP::REDUCE(p) => {
+                    let p = EvalParser::from(self, p);
+                    let (list, f) = p.eval()?.take_fun1()?;
+                    let mut items = list_items(list, "REDUCE").into_iter();
+                    match items.next() {
+                        Some(first) => Some(items.try_fold(first, |acc, item| self.call(f, (acc, item).into()))?),
+                        None => None
+                    }.into()
+                },
+                OP::FIND(p) => {
+                    let p = EvalParser::from(self, p);
+                    let (list, f) = p.eval()?.take_fun1()?;
+                    let mut found = None;
+                    for item in list_items(list, "FIND") {
+                        if self.call(f, item.clone())?._as::<bool>() {
+                            found = Some(item);
+                            break;
+                        }
+                    }
+                    found.into()
+                },
+                OP::POSITION(p) => {
+                    let p = EvalParser::from(self, p);
+                    let (list, f) = p.eval()?.take_fun1()?;
+                    let mut found = None;
+                    for (i, item) in list_items(list, "POSITION").into_iter().enumerate() {
+                        if self.call(f, item)?._as::<bool>() {
+                            found = Some(i as u16);
+                            break;
+                        }
+                    }
+                    found.into()
+                },
+                OP::SUM(p) => {
+                    let p = EvalParser::from(self, p);
+                    let (list, zero) = p.eval()?.eval()?;
+                    list_items(list, "SUM").iter().try_fold(zero, |acc, item| {
+                        checked_add(&acc, item).ok_or_else(|| failed!("SUM: overflow: {:?} + {:?}", acc, item))
+                    })?
+                },
+                OP::COUNT(p) => {
+                    let p = EvalParser::from(self, p);
+                    let (list, f) = p.eval()?.take_fun1()?;
+                    let mut n = 0u16;
+                    for item in list_items(list, "COUNT") {
+                        if self.call(f, item)?._as::<bool>() {
+                            // Native lists can be longer than u16::MAX
+                            n = n.checked_add(1).ok_or_else(|| failed!("COUNT: overflow"))?;
+                        }
+                    }
+                    n.into()
+                },
+                OP::ZIP(_) => {
+                    let a = list_items(self.eval()?, "ZIP");
+                    let b = list_items(self.eval()?, "ZIP");
+                    a.into_iter().zip(b).collect::<Vec<_>>().into()
+                },
+                OP::ENUMERATE(_) => {
+                    list_items(self.eval()?, "ENUMERATE").into_iter().enumerate().map(|(i, item)| {
+                        (i as u16, item)
+                    }).collect::<Vec<_>>().into()
+                },
+                OP::RANGE(_) => (0..self.eval()?._as::<u16>()).collect::<Vec<_>>().into(),
+                OP::LIST_CONCAT(_) => {
+                    let mut a = list_items(self.eval()?, "LIST_CONCAT");
+                    a.extend(list_items(self.eval()?, "LIST_CONCAT"));
+                    a.into()
+                },
+                OP::PUSH(_) => {
+                    let mut a = list_items(self.eval()?, "PUSH");
+                    a.push(self.eval()?);
+                    a.into()
+                },
+                OP::REVERSE(_) => {
+                    let mut a = list_items(self.eval()?, "REVERSE");
+                    a.reverse();
+                    a.into()
+                },
+                OP::TAKE(_) => {
+                    let mut a = list_items(self.eval()?, "TAKE");
+                    a.truncate(self.eval()?._as::<u16>() as usize);
+                    a.into()
+                },
+                OP::SORT(_) => {
+                    let mut items = list_items(self.eval()?, "SORT");
+                    self.charge_sort(items.len())?;
+                    check_comparable(items.iter(), "SORT")?;
+                    items.sort_by(sort_order);
+                    items.into()
+                },
+                OP::SORT_BY_KEY(p) => {
+                    let p = EvalParser::from(self, p);
+                    let (list, f) = p.eval()?.take_fun1()?;
+                    let items = list_items(list, "SORT_BY_KEY");
+                    self.charge_sort(items.len())?;
+                    let mut keyed = items.into_iter().map(|item| {
+                        Ok((self.call(f, item.clone())?, item))
+                    }).collect::<Result<Vec<_>, EvalError>>()?;
+                    check_comparable(keyed.iter().map(|(key, _)| key), "SORT_BY_KEY")?;
+                    keyed.sort_by(|(a, _), (b, _)| sort_order(a, b));
+                    keyed.into_iter().map(|(_, item)| item).collect::<Vec<_>>().into()
+                },
+                OP::DEDUP(_) => {
+                    let mut items = list_items(self.eval()?, "DEDUP");
+                    self.charge(items.len() as u64)?;
+                    items.dedup();
+                    items.into()
+                },
+                OP::IS_SORTED(_) => {
+                    let items = list_items(self.eval()?, "IS_SORTED");
+                    self.charge(items.len() as u64)?;
+                    let mut sorted = true;
+                    for w in items.windows(2) {
+                        sorted &= total_cmp(&w[0], &w[1], "IS_SORTED")? != Ordering::Greater;
+                    }
+                    sorted.into()
+                },
+                OP::SLICE(_) => {
+                    let o = self.eval()?;
+                    let idx = self.eval()?._as::<u16>() as usize;
+                    match o {
+                        RD::List(vec) => {
+                            vec.get(idx..).ok_or_else(|| failed!("SLICE: index {} out of range ({})", idx, vec.len()))?.to_vec().into()
+                        },
+                        RD::Native(c) => {
+                            let len = c.len();
+                            if idx > len {
+                                fail!("SLICE: index {} out of range ({})", idx, len);
+                            }
+                            if idx == len { Vec::<RD>::new().into() } else { c.slice(idx).into() }
+                        },
+                        _ => panic!("SLICE: Expecting RD::List")
+                    }
+                },
+                OP::INDEX(p) => {
+                    let p = EvalParser::from(self, p);
+                    let C(rd, p) = p.eval()?;
+                    let idx = p.eval_as::<u16>()? as usize;
+
+                    let oob = |len: usize| failed!("INDEX: index {} out of range ({})", idx, len);
+                    match rd {
+                        RD::List(vec) => vec.get(idx).ok_or_else(|| oob(vec.len()))?.clone(),
+                        RD::Tuple(vec) => vec.get(idx).ok_or_else(|| oob(vec.len()))?.clone(),
+                        RD::Native(p) => {
+                            if idx >= p.len() {
+                                return Err(oob(p.len()));
+                            }
+                            (*p).index(idx).into()
+                        },
+                        _ => panic!("INDEX: Expecting RD::[List,Tuple,Native], got: {:?}", rd)
+                    }
+                },
+            _ => unreachable!()
+        })
+    }
+
+    fn fetch(&mut self, buf: &'static [u8]) -> Result<RD, EvalError> {
         let prev = *self.buf;
         *self.buf = buf;
-        let out = self.eval();
+        let out = self.eval()?;
         *self.buf = prev;
-        out
+        Ok(out)
+    }
+
+    /*
+     * Cost accounting. Every op costs 1, and ops whose work is not bounded by
+     * the program size (ie, sorting) charge extra.
+     */
+    pub fn charge(&mut self, units: u64) -> Result<(), EvalError> {
+        self.cost = self.cost.saturating_add(units);
+        match self.ctx.cost_limit {
+            Some(limit) if self.cost > limit => {
+                Err(EvalError::Limit(format!("BUDGET: cost limit exceeded: {} > {}", self.cost, limit)))
+            },
+            _ => Ok(())
+        }
+    }
+
+    pub fn cost(&self) -> u64 {
+        self.cost
     }
 
-    fn fn_map(&mut self, p: impl HasParser<R=tlist!(RR<()>, RR<EncodedFunction>)>) -> Vec<RD> {
-        fn_map!(|self, p, f, val| {
+    // n * log2(n) comparisons
+    fn charge_sort(&mut self, n: usize) -> Result<(), EvalError> {
+        let log2 = usize::BITS - n.leading_zeros();
+        self.charge(n as u64 * log2 as u64)
+    }
+
+    /*
+     * Function calls get a frame: the argument slot is saved and restored on
+     * return (as are LET slots), so a function can call itself.
+     *
+     * A foreign function called by the program runs with fresh vars, and the
+     * program's vars are set aside in own_vars. If it calls back into one of
+     * the program's functions (eg, a closure passed to it), that function runs
+     * against the program's vars again, so it sees the LET and input values in
+     * scope where the foreign function was called. It still can't use
+     * privileged opcodes. Likewise foreign functions calling each other share
+     * the foreign vars.
+     */
+    fn call(&mut self, f: &'static EncodedFunction, input: RD) -> Result<RD, EvalError> {
+        let max_depth = self.ctx.max_call_depth.unwrap_or(DEFAULT_MAX_CALL_DEPTH);
+        if self.call_depth >= max_depth {
+            return Err(EvalError::Limit(format!("CALL: max call depth exceeded: {}", max_depth)));
+        }
+        self.call_depth += 1;
+        let code = std::mem::replace(&mut self.code, &f.1);
+        let out = if self.is_foreign(f) && !self.in_foreign {
+            let mut vars = vec![RD::Unit(); MAX_VARS];
+            vars[f.0 as usize] = input;
+            self.own_vars.push(std::mem::replace(&mut self.vars, vars));
+            self.in_foreign = true;
+            let out = self.fetch(&f.1)?;
+            self.in_foreign = false;
+            self.vars = self.own_vars.pop().unwrap();
+            out
+        } else if !self.is_foreign(f) && self.in_foreign {
+            // Leaves an empty frame, so own_vars still shows a foreign caller
+            let mut vars = std::mem::take(self.own_vars.last_mut().unwrap());
+            let arg = std::mem::replace(&mut vars[f.0 as usize], input);
+            let saved = std::mem::replace(&mut self.vars, vars);
+            self.in_foreign = false;
+            let out = self.fetch(&f.1)?;
+            self.in_foreign = true;
+            let mut vars = std::mem::replace(&mut self.vars, saved);
+            vars[f.0 as usize] = arg;
+            *self.own_vars.last_mut().unwrap() = vars;
+            out
+        } else {
+            let saved = std::mem::replace(&mut self.vars[f.0 as usize], input);
+            let out = self.fetch(&f.1)?;
+            self.vars[f.0 as usize] = saved;
+            out
+        };
+        self.code = code;
+        self.call_depth -= 1;
+        Ok(out)
+    }
+
+    /*
+     * Functions defined by the running program are trusted. Any other function
+     * value (ie, one passed in as input) is verified before it is first called,
+     * and runs with its own vars so it can't read or clobber the caller's.
+     */
+    fn check_function(&mut self, f: &'static EncodedFunction) -> Result<(), EvalError> {
+        let ptr = f as *const _ as usize;
+        if !self.own_functions.contains(&ptr) && !self.verified_functions.contains(&ptr) {
+            verify_function(f).map_err(|e| failed!("CALL: invalid function: {}", e))?;
+            self.verified_functions.insert(ptr);
+        }
+        Ok(())
+    }
+
+    fn is_foreign(&self, f: &EncodedFunction) -> bool {
+        !self.own_functions.contains(&(f as *const _ as usize))
+    }
+
+    fn fn_map(&mut self, p: impl HasParser<R=tlist!(RR<()>, RR<EncodedFunction>)>) -> Result<Vec<RD>, EvalError> {
+        Ok(fn_map!(|self, p, f, val| {
             match val {
-                RD::List(vec) => vec.iter().map(f).collect(),
-                RD::Option(o) => o.as_ref().into_iter().map(f).collect(),
-                RD::Native(c) => c.iter().map(|o| f(&o)).collect(),
+                RD::List(vec) => vec.iter().map(f).collect::<Result<_, _>>()?,
+                RD::Option(o) => o.as_ref().into_iter().map(f).collect::<Result<_, _>>()?,
+                RD::Native(c) => c.iter().map(|o| f(&o)).collect::<Result<_, _>>()?,
                 _ => panic!("eval::fn_map: invalid type")
             }
+        }))
+    }
+
+    fn eval_bytes(&mut self, op: &str) -> Result<&'static [u8], EvalError> {
+        let rd = self.eval()?;
+        Ok(rd.as_bytes().unwrap_or_else(|| panic!("{}: expected bytes, got: {:?}", op, rd)))
+    }
+
+    fn eval_seeds(&mut self) -> Result<(Vec<&'static [u8]>, Pubkey), EvalError> {
+        let seeds = match self.eval()? {
+            RD::List(v) => v.iter().map(|s| s.as_bytes().expect("PDA: expected Buffer seed")).collect(),
+            o => panic!("PDA: expected seeds list, got: {:?}", o)
+        };
+        let program_id = Pubkey::new_from_array(*self.eval()?._as::<&'static [u8; 32]>());
+        Ok((seeds, program_id))
+    }
+
+    fn account(&self, idx: u16) -> Result<&'static AccountInfo<'static>, EvalError> {
+        let accounts = self.ctx.accounts;
+        accounts.get(idx as usize).ok_or_else(|| {
+            failed!("ACCOUNT: index {} out of range ({} accounts)", idx, accounts.len())
         })
     }
 
-    fn take_op(&mut self) -> OP {
-        let op = self.buf.next().into();
-        //println!("OP IS: {:?}", op);
-        op
+    fn resources(&self, op: &str) -> Result<Rc<RefCell<dyn ResourceStore>>, EvalError> {
+        self.ctx.resources.clone().ok_or_else(|| failed!("{}: no resources", op))
+    }
+
+    #[cfg(feature = "anchor")]
+    fn invoker(&self) -> &'static dyn Invoker {
+        self.ctx.invoker.unwrap_or(&SolanaInvoker)
+    }
+
+    fn take_op(&mut self) -> Result<OP, EvalError> {
+        let offset = self.buf.as_ptr() as usize - self.code.as_ptr() as usize;
+        match OP::try_from(self.buf.next()) {
+            Ok(OP::NEVER(_)) => Err(EvalError::InvalidOpcode(0, offset)),
+            Ok(op) => Ok(op),
+            Err(byte) => Err(EvalError::InvalidOpcode(byte, offset)),
+        }
     }
 
     #[inline]
@@ -368,6 +1092,63 @@ impl Evaluator {
 }
 
 
+fn list_items(rd: RD, op: &str) -> Vec<RD> {
+    match rd {
+        RD::List(v) => v.to_vec(),
+        RD::Native(c) => c.iter().collect(),
+        o => panic!("{}: expected list, got: {:?}", op, o)
+    }
+}
+
+fn total_cmp(a: &RD, b: &RD, op: &str) -> Result<Ordering, EvalError> {
+    a.partial_cmp(b).ok_or_else(|| failed!("{}: not comparable: {:?}, {:?}", op, a, b))
+}
+
+// Native and function values have no order, sorting them fails
+fn check_comparable<'a>(items: impl Iterator<Item=&'a RD> + Clone, op: &str) -> Result<(), EvalError> {
+    for (a, b) in items.clone().zip(items.skip(1)) {
+        total_cmp(a, b, op)?;
+    }
+    Ok(())
+}
+
+// For sort_by, which needs a total order: values that aren't comparable
+// (nested, or of different types) are ordered by tag
+fn sort_order(a: &RD, b: &RD) -> Ordering {
+    match (a, b) {
+        (RD::List(a), RD::List(b)) | (RD::Tuple(a), RD::Tuple(b)) => {
+            a.iter().zip(b.iter())
+                .map(|(a, b)| sort_order(a, b))
+                .find(|o| o.is_ne())
+                .unwrap_or_else(|| a.len().cmp(&b.len()))
+        },
+        (RD::Option(a), RD::Option(b)) => match (a.as_ref(), b.as_ref()) {
+            (Some(a), Some(b)) => sort_order(a, b),
+            (a, b) => a.is_some().cmp(&b.is_some())
+        },
+        _ => a.partial_cmp(b).unwrap_or_else(|| a.tag().cmp(&b.tag()))
+    }
+}
+
+macro_rules! checked_int_op {
+    ($name:ident, $op:ident) => {
+        fn $name(a: &RD, b: &RD) -> Option<RD> {
+            Some(match (a, b) {
+                (RD::U8(a),  RD::U8(b))  => a.$op(*b)?.into(),
+                (RD::U16(a), RD::U16(b)) => a.$op(*b)?.into(),
+                (RD::U32(a), RD::U32(b)) => a.$op(*b)?.into(),
+                (RD::U64(a), RD::U64(b)) => a.$op(**b)?.into(),
+                (RD::U128(a), RD::U128(b)) => a.$op(**b)?.into(),
+                (a, b) => panic!("{}: unexpected: {:?}, {:?}", stringify!($op), a, b)
+            })
+        }
+    };
+}
+checked_int_op!(checked_add, checked_add);
+checked_int_op!(checked_sub, checked_sub);
+checked_int_op!(checked_mul, checked_mul);
+
+
 struct C<'a, O, T: TList>(pub O, pub EvalParser<'a, T>);
 
 
@@ -385,46 +1166,49 @@ macro_rules! parser_taker {
     (<$($param:ident$(: $tr0:ident)?),*> ($($matcher:ty),*), $name:ident, $(@<$($f:ident: $t:ident)*>)?$ret:ty, |$self:ident| $expr:expr) => {
         #[allow(unused)]
         impl<'a, B, T: TList$(, $param$(: $tr0)?)*> EvalParser<'a, wrap_tcons!($($matcher,)* TCons<B, T>)> {
-            pub fn $name$(<$($f: $t)*>)?($self) -> C<'a, $ret, TCons<B, T>> {
-                C($expr, EvalParser($self.0, PhantomData::default()))
+            pub fn $name$(<$($f: $t)*>)?($self) -> Result<C<'a, $ret, TCons<B, T>>, EvalError> {
+                Ok(C($expr?, EvalParser($self.0, PhantomData::default())))
             }
         }
         #[allow(unused)]
         impl<'a, E, B, T: TList$(, $param$(: $tr0)?)*> C<'a, E, wrap_tcons!($($matcher,)* TCons<B, T>)> {
-            pub fn $name$(<$($f: $t)*>)?($self) -> C<'a, (E, $ret), TCons<B, T>> {
-                let C(r, p) = $self.1.$name();
-                C(($self.0, r), p)
+            pub fn $name$(<$($f: $t)*>)?($self) -> Result<C<'a, (E, $ret), TCons<B, T>>, EvalError> {
+                let C(r, p) = $self.1.$name()?;
+                Ok(C(($self.0, r), p))
             }
         }
         #[allow(unused)]
         impl<'a $(, $param$(: $tr0)?)*> EvalParser<'a, wrap_tcons!($($matcher,)* ())> {
-            pub fn $name$(<$($f: $t)*>)?($self) -> $ret { $expr }
+            pub fn $name$(<$($f: $t)*>)?($self) -> Result<$ret, EvalError> { $expr }
         }
         #[allow(unused)]
         impl<'a, E $(, $param$(: $tr0)?)*> C<'a, E, wrap_tcons!($($matcher,)* ())> {
-            pub fn $name$(<$($f: $t)*>)?($self) -> (E, $ret) {
-                ($self.0, $self.1.$name())
+            pub fn $name$(<$($f: $t)*>)?($self) -> Result<(E, $ret), EvalError> {
+                Ok(($self.0, $self.1.$name()?))
             }
         }
     };
 }
 parser_taker!(<> (RR<()>), eval_as, @<O: FromRD> O, |self| {
-    self.0.eval()._as()
+    Ok(self.0.eval()?._as())
 });
 parser_taker!(<> (RR<()>), eval, RD, |self| {
     self.0.eval()
 });
 parser_taker!(<S: Decode> (S), take, S, |self| {
-    self.0.buf.decode()
+    Ok(self.0.buf.decode())
 });
 parser_taker!(<N> (Skippable, N), skip, (), |self| {
     let n: u16 = self.0.buf.decode();
     self.0.buf.skip_bytes(n as usize);
+    Ok(())
 });
 parser_taker!(<> (Skippable, RR<()>), eval, RD, |self| {
     self.0.buf.skip_bytes(2);
     self.0.eval()
 });
 parser_taker!(<> (RR<EncodedFunction>), take_fun1, &'static EncodedFunction, |self| {
-    self.0.eval()._as()
+    let f = self.0.eval()?._as();
+    self.0.check_function(f)?;
+    Ok(f)
 });
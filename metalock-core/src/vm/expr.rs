@@ -1,4 +1,5 @@
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::ops::DerefMut;
@@ -11,15 +12,67 @@ use crate::types::decode::*;
 use crate::types::encode::*;
 use crate::types::schema::*;
 use crate::types::data::*;
+use crate::types::native::NativePod;
+use crate::types::newval::schema_has_function;
+#[cfg(feature = "anchor")]
+use crate::types::native::MetalockProxyCall;
 use crate::{impl_into, impl_deref, each_field};
+use super::verify::{Verifier, VerifyOp};
 
 use dyn_clone::{clone_trait_object, DynClone};
+use solana_program::pubkey::Pubkey;
+#[cfg(feature = "anchor")]
+use solana_program::instruction::AccountMeta;
 
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-#[repr(u8)]
-#[allow(non_camel_case_types)]
-pub enum OP {
+/*
+ * The opcode table. An opcode added after the first format version is tagged
+ * with the version that introduced it, ie `NAME(Parser) = 0xNN @ 2`, see
+ * header.rs.
+ */
+macro_rules! define_ops {
+    ($($(#[$m:meta])* $name:ident($($p:ty)?) = $id:literal $(@ $since:literal)?,)*) => {
+        #[derive(Clone, Debug, PartialEq, Eq)]
+        #[repr(u8)]
+        #[allow(non_camel_case_types)]
+        pub enum OP {
+            $($(#[$m])* $name($($p)?) = $id,)*
+        }
+        // Err is the byte, which is not an opcode in this build
+        impl TryFrom<u8> for OP {
+            type Error = u8;
+            fn try_from(byte: u8) -> Result<OP, u8> {
+                match byte {
+                    $($(#[$m])* $id => Ok(OP::$name($(<$p>::default())?)),)*
+                    _ => Err(byte)
+                }
+            }
+        }
+        impl OP {
+            // The format version that introduced the opcode
+            pub fn since(&self) -> u8 {
+                match self {
+                    $($(#[$m])* OP::$name(..) => op_since!($($since)?),)*
+                }
+            }
+            pub(crate) fn verify_operands(&self, v: &mut Verifier) -> Result<(), String> {
+                match self {
+                    $($(#[$m])* OP::$name(..) => <op_payload!($($p)?) as VerifyOp>::verify(v),)*
+                }
+            }
+        }
+    };
+}
+macro_rules! op_since {
+    () => { 1 };
+    ($v:literal) => { $v };
+}
+macro_rules! op_payload {
+    () => { () };
+    ($p:ty) => { $p };
+}
+
+define_ops! {
     NEVER(()) = 0x00,
     CALL(CallParser) = 0x02,
     FETCH() = 0x03,
@@ -32,6 +85,7 @@ pub enum OP {
     FROM_SOME(FromSomeParser) = 0x21,
     OR_SOME(OrSomeParser) = 0x22,
     EQ(EqualsParser) = 0x23,
+    LT(LessThanParser) = 0x24,
     GET_STRUCT_FIELD(GetStructFieldParser) = 0x30,
     SET_STRUCT_FIELD(SetStructFieldParser) = 0x31,
     MAP(MapParser) = 0x40,
@@ -39,22 +93,88 @@ pub enum OP {
     ANY(AnyParser) = 0x42,
     EACH(EachParser) = 0x43,
     LEN(LengthParser) = 0x44,
+    FILTER(FilterParser) = 0x45,
+    FOLD(FoldParser) = 0x46,
+    REDUCE(ReduceParser) = 0x47,
+    FIND(FindParser) = 0x48,
+    POSITION(PositionParser) = 0x49,
+    SUM(SumParser) = 0x4a,
+    COUNT(CountParser) = 0x4b,
+    ZIP(ZipParser) = 0x4c,
+    ENUMERATE(EnumerateParser) = 0x4d,
+    RANGE(RangeParser) = 0x4e,
+    LIST_CONCAT(ListConcatParser) = 0x4f,
     INDEX(IndexParser) = 0x50,
+    PUSH(PushParser) = 0x51,
+    REVERSE(ReverseParser) = 0x52,
+    TAKE(TakeParser) = 0x53,
     SLICE(SliceParser) = 0x55,
+    SORT(SortParser) = 0x56,
+    SORT_BY_KEY(SortByKeyParser) = 0x57,
+    DEDUP(DedupParser) = 0x58,
+    IS_SORTED(IsSortedParser) = 0x59,
     VAR(VarParser) = 0x60,
     SETVAR(SetVarParser) = 0x61,
+    LET(LetParser) = 0x62,
     ADD(AddParser) = 0x70,
+    SUB(SubParser) = 0x71,
+    MUL(MulParser) = 0x72,
     IF(IfParser) = 0x80,
-    //#[cfg(feature = "anchor")]
-    //INVOKE_SIGNED(InvokeSignedParser) = 0xB1,
-    //GET_INVOKE_RETURN(GetInvokeReturnParser) = 0xBA,
+    REPEAT(RepeatParser) = 0x81,
+    WHILE(WhileParser) = 0x82,
+    HOSTCALL(HostCallParser) = 0x90,
+    ACCOUNT(AccountFieldParser) = 0xA0,
+    ACCOUNT_NATIVE(AccountNativeParser) = 0xA1,
+    CLOCK(ClockFieldParser) = 0xA8,
+    RENT_MINIMUM_BALANCE(RentMinimumBalanceParser) = 0xA9,
+    RESOURCE_GET(ResourceGetParser) = 0xAC @ 2,
+    RESOURCE_SET(ResourceSetParser) = 0xAD @ 2,
+    #[cfg(feature = "anchor")]
+    PROXY_CALL(ProxyCallParser) = 0xB0,
+    #[cfg(feature = "anchor")]
+    INVOKE_SIGNED(InvokeSignedParser) = 0xB1,
+    CREATE_PROGRAM_ADDRESS(CreateProgramAddressParser) = 0xB4,
+    FIND_PROGRAM_ADDRESS(FindProgramAddressParser) = 0xB5,
+    #[cfg(feature = "anchor")]
+    GET_INVOKE_RETURN(GetInvokeReturnParser) = 0xBA,
     PANIC(PanicParser) = 0xc0,
     ASSERT(AssertParser) = 0xc1,
+    TRY(TryParser) = 0xc2,
+    ASSERT_CODE(AssertCodeParser) = 0xc3,
+    SHA256(Sha256Parser) = 0xd0,
+    KECCAK256(Keccak256Parser) = 0xd1,
+    BLAKE3(Blake3Parser) = 0xd2,
+    ED25519_VERIFY(Ed25519VerifyParser) = 0xd4,
+    SECP256K1_RECOVER(Secp256k1RecoverParser) = 0xd5,
+    CONCAT(ConcatParser) = 0xd8,
+    STR_CONCAT(StrConcatParser) = 0xd9,
+    BYTES_SLICE(BytesSliceParser) = 0xda,
+    STARTS_WITH(StartsWithParser) = 0xdb,
+    ENDS_WITH(EndsWithParser) = 0xdc,
+    CONTAINS(ContainsParser) = 0xdd,
+    BYTE_AT(ByteAtParser) = 0xde,
+    TO_BUFFER(ToBufferParser) = 0xdf,
+    UTF8_DECODE(Utf8DecodeParser) = 0xe0,
+    FROM_LE_BYTES(FromLeBytesParser) = 0xe1,
+    TO_LE_BYTES(ToLeBytesParser) = 0xe2,
 }
 const _: () = assert!(std::mem::size_of::<OP>() == 1);
-impl_into!([], OP, u8, |self| unsafe { std::mem::transmute::<u8, OP>(self) });
 impl_into!([], u8, OP, |self| unsafe { std::mem::transmute::<OP, u8>(self) });
 
+impl OP {
+    // Opcodes that reach the host, accounts or raw memory, which function
+    // values from elsewhere can't use, see verify_function
+    pub fn is_privileged(&self) -> bool {
+        match self {
+            OP::GET_STRUCT_FIELD(_) | OP::SET_STRUCT_FIELD(_) | OP::ACCOUNT_NATIVE(_) => true,
+            OP::HOSTCALL(_) | OP::RESOURCE_SET(_) => true,
+            #[cfg(feature = "anchor")]
+            OP::INVOKE_SIGNED(_) => true,
+            _ => false
+        }
+    }
+}
+
 
 
 #[derive(Debug, Clone)]
@@ -86,17 +206,31 @@ impl<I> Decode for VarId<I> {
         Ok(VarId::from(u16::rd_decode(buf)?))
     }
 }
+/*
+ * A VarId created with new() is assigned a slot by each EncodeContext that
+ * encodes it, so the same expression can be compiled more than once.
+ * VarId::from is a fixed slot.
+ */
+#[repr(C)]
+struct VarSlot(u16, bool);
+
 impl<I> VarId<I> {
     pub fn new() -> Self {
-        Self::from(u16::MAX)
+        Self::leak(VarSlot(u16::MAX, true))
     }
     pub fn from(var_id: u16) -> VarId<I> {
-        let ptr = Box::leak(Box::new(var_id)) as *mut u16 as *mut () as usize;
+        Self::leak(VarSlot(var_id, false))
+    }
+    fn leak(slot: VarSlot) -> VarId<I> {
+        let ptr = Box::leak(Box::new(slot)) as *mut VarSlot as *mut () as usize;
         VarId(ptr, PhantomData::default())
     }
+    fn is_dynamic(&self) -> bool {
+        unsafe { (*(self.0 as *const VarSlot)).1 }
+    }
     pub fn populate(&mut self, ctx: &mut EncodeContext) {
-        if **self == u16::MAX {
-            **self = ctx.next();
+        if self.is_dynamic() {
+            **self = ctx.var_slot(self.0);
         }
     }
 }
@@ -172,16 +306,82 @@ impl<A: SchemaType + Into<RD>> ToRR<A> for &A { fn rr(&self) -> RR<A> { RR::val(
 #[derive(Default, Debug)]
 pub struct EncodeContext {
     next_var_id: u16,
+    free_var_ids: Vec<u16>,
+    var_slots: BTreeMap<usize, u16>,
+    // Slots bound by LET and function inputs, and the function depth they
+    // are bound at
+    bindings: BTreeMap<u16, usize>,
+    // Bindings referred to by functions nested in their scope
+    captured: BTreeSet<u16>,
+    function_depth: usize,
+    // Found while encoding, see check
+    errors: Vec<String>,
     pub val_size: usize
 }
 impl EncodeContext {
     pub fn new() -> EncodeContext {
         Default::default()
     }
+    // Var slot 0 is reserved for the program input
+    pub fn program() -> EncodeContext {
+        EncodeContext { next_var_id: 1, ..Default::default() }
+    }
     pub fn next(&mut self) -> u16 {
+        if let Some(id) = self.free_var_ids.pop() {
+            return id;
+        }
         self.next_var_id += 1;
         self.next_var_id - 1
     }
+    // Return a slot that is no longer live, to be reused by next()
+    pub fn release(&mut self, var_id: u16) {
+        self.free_var_ids.push(var_id);
+    }
+    fn var_slot(&mut self, var: usize) -> u16 {
+        if let Some(id) = self.var_slots.get(&var) {
+            return *id;
+        }
+        let id = self.next();
+        self.var_slots.insert(var, id);
+        id
+    }
+    // Number of var slots used
+    pub fn var_slots(&self) -> u16 {
+        self.next_var_id
+    }
+
+    // Fails if the encoded expression is invalid, ie a function escapes
+    pub fn check(&self) -> Result<(), String> {
+        match self.errors.first() {
+            Some(e) => Err(e.clone()),
+            None => Ok(())
+        }
+    }
+
+    /*
+     * Functions read outer bindings when they are called, not when they are
+     * created: the program's bindings in scope at the call, which when a
+     * foreign function value makes the call are those in scope where the
+     * program called the foreign function. So a function can't be returned
+     * out of the scope of a binding it refers to. That is refused here, as far
+     * as the type of the scope tells: its value can't hold a function if a
+     * nested function refers to the binding.
+     */
+    fn bind(&mut self, var_id: u16) {
+        self.bindings.insert(var_id, self.function_depth);
+        self.captured.remove(&var_id);
+    }
+    fn refer(&mut self, var_id: u16) {
+        if self.bindings.get(&var_id).is_some_and(|depth| *depth < self.function_depth) {
+            self.captured.insert(var_id);
+        }
+    }
+    fn unbind(&mut self, var_id: u16, out: &Schema) {
+        self.bindings.remove(&var_id);
+        if self.captured.remove(&var_id) && schema_has_function(out) {
+            self.errors.push(format!("a function refers to var {} and may outlive it", var_id));
+        }
+    }
 }
 
 pub(crate) trait HasParser {
@@ -193,6 +393,7 @@ macro_rules! parser_type {
     ((RR<Function<$($t:tt)*)) => { RR<EncodedFunction> };
     ((RR $($t:tt)*)) => { RR<()> };
     ((Skippable)) => { Skippable };
+    ((Vec<RR $($t:tt)*)) => { Vec<RR<()>> };
     ((VarId $($t:tt)*)) => { VarId<()> };
     ((PrependSchema::<$t:ty>)) => { PrependSchema<()> };
     ($t:ident) => { $t };
@@ -273,12 +474,24 @@ impl<I: SchemaType, O: SchemaType> SchemaType for Function<I, O> {
 opcode!(#VAL, Function<I, O>, Function<I: SchemaType, O: SchemaType>(
         (VarId<I>) [(PrependSchema::<Function<I, O>>)],
         (RR<O>) [Skippable]
-));
+), |self, ctx| {
+    let var = PrependSchema::<Function<I, O>>::op_encode(self.0.op_encode(ctx), ctx);
+    ctx.function_depth += 1;
+    ctx.bind(*self.0);
+    let body = Skippable::op_encode(self.1.op_encode(ctx), ctx);
+    ctx.function_depth -= 1;
+    ctx.unbind(*self.0, &O::to_schema());
+    OpTree::Op(Some(OP::VAL(Default::default()).into()), vec![var, body])
+});
 
 opcode!(#CALL, O, Call<I: SchemaType, O: SchemaType>((RR<I>), (RR<Function<I, O>>)));
 
 opcode!(#EQ, bool, Equals<T>((RR<T>), (RR<T>)));
+opcode!(#LT, bool, LessThan<T: PartialOrd>((RR<T>), (RR<T>)));
 opcode!(#ADD, T, Add<T: std::ops::Add>((RR<T>), (RR<T>)));
+// Panic on overflow
+opcode!(#SUB, T, Sub<T: std::ops::Sub>((RR<T>), (RR<T>)));
+opcode!(#MUL, T, Mul<T: std::ops::Mul>((RR<T>), (RR<T>)));
 
 opcode!(#AND, bool, And<>((RR<bool>), (RR<bool>) [Skippable]));
 opcode!(#OR,  bool, Or<>((RR<bool>), (RR<bool>) [Skippable]));
@@ -290,6 +503,55 @@ impl HasLen for String { }
 
 opcode!(#LEN, u16, Length<I: HasLen>((RR<I>)));
 
+pub trait HasBytes { }
+impl HasBytes for Buffer { }
+impl HasBytes for String { }
+impl HasBytes for [u8; 32] { }
+impl HasBytes for Pubkey { }
+
+opcode!(#SHA256,    [u8; 32], Sha256<I: HasBytes>((RR<I>)));
+opcode!(#KECCAK256, [u8; 32], Keccak256<I: HasBytes>((RR<I>)));
+opcode!(#BLAKE3,    [u8; 32], Blake3<I: HasBytes>((RR<I>)));
+opcode!(#CONCAT,    Buffer,   Concat<A: HasBytes, B: HasBytes>((RR<A>), (RR<B>)));
+opcode!(#STR_CONCAT, String,  StrConcat<>((RR<String>), (RR<String>)));
+
+/*
+ * Byte level operations on String and Buffer. Offsets are in bytes, a String
+ * may only be sliced on char boundaries.
+ */
+pub trait HasSlice { }
+impl HasSlice for Buffer { }
+impl HasSlice for String { }
+
+// (input, start, end)
+opcode!(#BYTES_SLICE, I,    BytesSlice<I: HasSlice>((RR<I>), (RR<u16>), (RR<u16>)));
+opcode!(#STARTS_WITH, bool, StartsWith<I: HasBytes>((RR<I>), (RR<I>)));
+opcode!(#ENDS_WITH,   bool, EndsWith<I: HasBytes>((RR<I>), (RR<I>)));
+opcode!(#CONTAINS,    bool, Contains<I: HasBytes>((RR<I>), (RR<I>)));
+opcode!(#BYTE_AT,     u8,   ByteAt<I: HasBytes>((RR<I>), (RR<u16>)));
+opcode!(#TO_BUFFER,   Buffer, ToBuffer<I: HasBytes>((RR<I>)));
+// None if not valid utf8
+opcode!(#UTF8_DECODE, Option<String>, Utf8Decode<>((RR<Buffer>)));
+
+/*
+ * Little endian integers, the u8 is the byte width of T
+ */
+pub trait LeInt { const SIZE: u8; }
+impl LeInt for u8 { const SIZE: u8 = 1; }
+impl LeInt for u16 { const SIZE: u8 = 2; }
+impl LeInt for u32 { const SIZE: u8 = 4; }
+impl LeInt for u64 { const SIZE: u8 = 8; }
+impl LeInt for u128 { const SIZE: u8 = 16; }
+
+// (input, offset)
+opcode!(#FROM_LE_BYTES, T, FromLeBytes<I: HasBytes, T: LeInt>((RR<I>), (RR<u16>), u8, (PhantomData<T>)));
+opcode!(#TO_LE_BYTES, Buffer, ToLeBytes<T: LeInt>((RR<T>)));
+
+// (pubkey, message, signature)
+opcode!(#ED25519_VERIFY, bool, Ed25519Verify<M: HasBytes>((RR<Pubkey>), (RR<M>), (RR<Buffer>)));
+// (message hash, recovery id, signature) => 64 byte pubkey
+opcode!(#SECP256K1_RECOVER, Option<Buffer>, Secp256k1Recover<>((RR<[u8; 32]>), (RR<u8>), (RR<Buffer>)));
+
 opcode!(#NOT, bool, Not<>((RR<bool>)));
 
 opcode!(#VAL, A, Val<A: SchemaType>(RD [(PrependSchema::<A>)], (PhantomData<A>)));
@@ -306,13 +568,80 @@ impl<A: SchemaType + Into<RD>> From<A> for Val<A> {
 
 
 opcode!(#IF, O, If<O: Clone>((RR<bool>), (RR<O>) [Skippable], (RR<O>) [Skippable]));
-//#[cfg(feature = "anchor")]
-//opcode!(#INVOKE_SIGNED, (), InvokeSigned<>((RR<MetalockProxyCall>)));
-//opcode!(#GET_INVOKE_RETURN, Buffer, GetInvokeReturn<>());
+
+/*
+ * Bounded loops, threading an accumulator through a Function. Each iteration
+ * is charged to the budget.
+ */
+// (n, init, (i, acc) => acc)
+opcode!(#REPEAT, A, Repeat<A: SchemaType>((RR<u16>), (RR<A>), (RR<Function<(u16, A), A>>)));
+// (max iterations, init, acc => continue, acc => acc), fails if the bound is reached
+opcode!(#WHILE, A, While<A: SchemaType>((RR<u16>), (RR<A>), (RR<Function<A, bool>>), (RR<Function<A, A>>)));
+#[cfg(feature = "anchor")]
+opcode!(#PROXY_CALL, MetalockProxyCall, ProxyCall<>((RR<Pubkey>), (RR<Buffer>), (RR<Vec<AccountMeta> >)));
+#[cfg(feature = "anchor")]
+opcode!(#INVOKE_SIGNED, (), InvokeSigned<>((RR<MetalockProxyCall>)));
+#[cfg(feature = "anchor")]
+opcode!(#GET_INVOKE_RETURN, Buffer, GetInvokeReturn<>());
+
+/*
+ * Read only access to the accounts in the EvaluatorContext
+ */
+pub mod account_field {
+    pub const KEY: u8 = 0;
+    pub const OWNER: u8 = 1;
+    pub const LAMPORTS: u8 = 2;
+    pub const IS_SIGNER: u8 = 3;
+    pub const IS_WRITABLE: u8 = 4;
+    pub const DATA: u8 = 5;
+}
+opcode!(#ACCOUNT, R, AccountField<R>((RR<u16>), u8, (PhantomData<R>)));
+// A typed view of the account data at offset (ie, 8 to skip an anchor discriminator)
+opcode!(#ACCOUNT_NATIVE, S, AccountNative<S: NativePod>(u16 [(PrependSchema::<S>)], (RR<u16>), (PhantomData<S>)));
+
+/*
+ * Resources from the host, see ResourceStore
+ */
+opcode!(#RESOURCE_GET, Option<T>, ResourceGet<T: SchemaType>((RR<String>) [(PrependSchema::<T>)], (PhantomData<T>)));
+// Fails unless the context allows writes
+opcode!(#RESOURCE_SET, (), ResourceSet<T: SchemaType>((RR<String>) [(PrependSchema::<T>)], (RR<T>)));
+
+/*
+ * Sysvars provided by the host in the EvaluatorContext
+ */
+pub mod clock_field {
+    pub const SLOT: u8 = 0;
+    pub const EPOCH: u8 = 1;
+    // negative timestamps read as 0
+    pub const UNIX_TIMESTAMP: u8 = 2;
+}
+opcode!(#CLOCK, u64, ClockField<>(u8));
+opcode!(#RENT_MINIMUM_BALANCE, u64, RentMinimumBalance<>((RR<u64>)));
+
+/*
+ * HOSTCALL is encoded as the function id, its signature (length prefixed,
+ * see host_signature), the number of args, and the args. Args are type
+ * erased, the evaluator checks them against the registered function.
+ */
+opcode!(#HOSTCALL, O, HostCall<O>(u16, Schema, (Vec<RR<()> >), (PhantomData<O>)), |self, ctx| {
+    let signature = [(self.1.0.len() as u16).rd_encode(), self.1.0.clone()].concat();
+    let mut trees = vec![OpTree::Data(self.0.rd_encode()), OpTree::Data(signature), OpTree::Data(vec![self.2.len() as u8])];
+    trees.extend(self.2.iter_mut().map(|a| a.op_encode(ctx)));
+    OpTree::Op(Some(OP::HOSTCALL(Default::default()).into()), trees)
+});
+
+// PDA derivation from seeds and program id
+opcode!(#CREATE_PROGRAM_ADDRESS, Option<Pubkey>, CreateProgramAddress<>((RR<Vec<Buffer> >), (RR<Pubkey>)));
+opcode!(#FIND_PROGRAM_ADDRESS, (Pubkey, u8), FindProgramAddress<>((RR<Vec<Buffer> >), (RR<Pubkey>)));
 
 
 opcode!(#PANIC, A, Panic<A>(String, (PhantomData<A>)));
 opcode!(#ASSERT, (), Assert<>((RR<bool>), (RR<String>) [Skippable]));
+// Fails with EvalError::Code, cheaper than a message
+opcode!(#ASSERT_CODE, (), AssertCode<>((RR<bool>), u32));
+// None if the body fails with a recoverable EvalError. Only vars are rolled
+// back, not resource writes, invokes or host calls
+opcode!(#TRY, Option<T>, Try<T>((RR<T>) [Skippable]));
 opcode!(#INDEX, O, Index<O>((RR<Vec<O> >), (RR<u16>)));
 opcode!(#SLICE, Vec<O>, Slice<O>((RR<Vec<O> >), (RR<u16>)));
 
@@ -322,6 +651,7 @@ pub(crate) fn ph<T: Default>() -> T { Default::default() }
 impl<I> OpEncode for VarId<I> {
     fn op_encode(&mut self, ctx: &mut EncodeContext) -> OpTree {
         self.populate(ctx);
+        ctx.refer(**self);
         OpTree::Data((**self).rd_encode())
     }
 }
@@ -333,6 +663,24 @@ impl<I: SchemaType> Var<I> {
 }
 opcode!(#SETVAR, (), SetVar<I>((VarId<I>), (RR<I>)));
 
+/*
+ * LET evaluates the value once into a var and then evaluates the body.
+ * The slot is assigned when encoding and released after the body, so
+ * sibling LETs share slots.
+ */
+opcode!(#LET, O, Let<I, O: SchemaType>((VarId<I>), (RR<I>), (RR<O>)), |self, ctx| {
+    let value = self.1.op_encode(ctx);
+    let var_id = ctx.next();
+    ctx.var_slots.insert(self.0.0, var_id);
+    *self.0 = var_id;
+    ctx.bind(var_id);
+    let body = self.2.op_encode(ctx);
+    ctx.unbind(var_id, &O::to_schema());
+    ctx.var_slots.remove(&self.0.0);
+    ctx.release(var_id);
+    OpTree::Op(Some(OP::LET(Default::default()).into()), vec![OpTree::Data(var_id.rd_encode()), value, body])
+});
+
 
 opcode!(#MAP, Option<O>, MapOption<I: SchemaType, O: SchemaType>((RR<Option<I>>), (RR<Function<I, O>>)));
 opcode!(#MAP, Vec<O>,    Map<I: SchemaType, O: SchemaType>((RR<Vec<I>>),          (RR<Function<I, O>>)));
@@ -340,6 +688,40 @@ opcode!(#ALL, bool,      All<I: SchemaType, V: IntoIterator<Item=I>>((RR<V>),
 opcode!(#ANY, bool,      Any<I: SchemaType, V: IntoIterator<Item=I>>((RR<V>),     (RR<Function<I, bool>>)));
 opcode!(#EACH, (),       Each<I: SchemaType, V: IntoIterator<Item=I> >((RR<V>),   (RR<Function<I, ()>>)));
 
+/*
+ * List combinators, these accept RD::List and RD::Native lists and always
+ * produce an RD::List. Functions of two arguments take a tuple.
+ */
+opcode!(#FILTER, Vec<I>,       Filter<I: SchemaType>((RR<Vec<I>>), (RR<Function<I, bool>>)));
+// (list, init, (acc, item) => acc)
+opcode!(#FOLD, A,              Fold<I: SchemaType, A: SchemaType>((RR<Vec<I>>), (RR<A>), (RR<Function<(A, I), A>>)));
+// None if the list is empty
+opcode!(#REDUCE, Option<I>,    Reduce<I: SchemaType>((RR<Vec<I>>), (RR<Function<(I, I), I>>)));
+opcode!(#FIND, Option<I>,      Find<I: SchemaType>((RR<Vec<I>>), (RR<Function<I, bool>>)));
+opcode!(#POSITION, Option<u16>, Position<I: SchemaType>((RR<Vec<I>>), (RR<Function<I, bool>>)));
+// (list, zero), panics on overflow
+opcode!(#SUM, I,               Sum<I: std::ops::Add>((RR<Vec<I>>), (RR<I>)));
+opcode!(#COUNT, u16,           Count<I: SchemaType>((RR<Vec<I>>), (RR<Function<I, bool>>)));
+// Truncated to the shorter list
+opcode!(#ZIP, Vec<(A, B)>,     Zip<A, B>((RR<Vec<A>>), (RR<Vec<B>>)));
+opcode!(#ENUMERATE, Vec<(u16, I)>, Enumerate<I>((RR<Vec<I>>)));
+// 0..n
+opcode!(#RANGE, Vec<u16>,      Range<>((RR<u16>)));
+opcode!(#LIST_CONCAT, Vec<I>,  ListConcat<I>((RR<Vec<I>>), (RR<Vec<I>>)));
+opcode!(#PUSH, Vec<I>,         Push<I>((RR<Vec<I>>), (RR<I>)));
+opcode!(#REVERSE, Vec<I>,      Reverse<I>((RR<Vec<I>>)));
+// At most n items
+opcode!(#TAKE, Vec<I>,         Take<I>((RR<Vec<I>>), (RR<u16>)));
+
+/*
+ * Sorting is stable and ascending, and charged to the budget up front
+ */
+opcode!(#SORT, Vec<I>,         Sort<I: PartialOrd>((RR<Vec<I>>)));
+opcode!(#SORT_BY_KEY, Vec<I>,  SortByKey<I: SchemaType, K: SchemaType>((RR<Vec<I>>), (RR<Function<I, K>>)));
+// Removes consecutive duplicates, like Vec::dedup
+opcode!(#DEDUP, Vec<I>,        Dedup<I>((RR<Vec<I>>)));
+opcode!(#IS_SORTED, bool,      IsSorted<I: PartialOrd>((RR<Vec<I>>)));
+
 opcode!(#TO_SOME, Option<I>, ToSome<I>((RR<I>)));
 opcode!(#FROM_SOME, I, FromSome<I>((RR<Option<I>>), (RR<I>) [Skippable]));
 opcode!(#OR_SOME, Option<I>, OrSome<I>((RR<Option<I>>), (RR<Option<I>>) [Skippable]));
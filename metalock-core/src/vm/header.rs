@@ -0,0 +1,184 @@
+
+use super::eval::MAX_VARS;
+use super::expr::OP;
+use super::verify::{program_info, ProgramInfo};
+
+
+/*
+ * Header for compiled programs, so that an evaluator can refuse code it
+ * can't run rather than misreading it:
+ *
+ *   MAGIC, version, required opcodes (256 bit bitmap), var slots (u16)
+ *
+ * The version is that of the opcode table (see define_ops); the bitmap lists
+ * the opcodes the program uses, which also catches opcodes behind features
+ * that the evaluator was built without. Evaluator::load recomputes both
+ * from the code, so a header can't understate what the code uses.
+ */
+
+// Not an opcode, so headerless code is refused
+pub const MAGIC: u8 = 0xfe;
+// Latest format version
+pub const VERSION: u8 = 2;
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpcodeSet(pub [u8; 32]);
+
+impl OpcodeSet {
+    // Opcodes of a format version that are in this build
+    pub fn version(version: u8) -> OpcodeSet {
+        let mut set = OpcodeSet::default();
+        for byte in 0..=255u8 {
+            match OP::try_from(byte) {
+                Ok(OP::NEVER(_)) | Err(_) => {},
+                Ok(op) => if op.since() <= version { set.insert(byte) }
+            }
+        }
+        set
+    }
+
+    pub fn insert(&mut self, byte: u8) {
+        self.0[byte as usize / 8] |= 1 << (byte % 8);
+    }
+
+    pub fn contains(&self, byte: u8) -> bool {
+        self.0[byte as usize / 8] & (1 << (byte % 8)) > 0
+    }
+
+    pub fn extend(&mut self, other: &OpcodeSet) {
+        self.0.iter_mut().zip(other.0).for_each(|(a, b)| *a |= b);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=u8> + '_ {
+        (0..=255u8).filter(|b| self.contains(*b))
+    }
+
+    // Opcodes in self and not in other
+    pub fn missing(&self, other: &OpcodeSet) -> Vec<u8> {
+        self.iter().filter(|b| !other.contains(*b)).collect()
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub opcodes: OpcodeSet,
+    pub var_slots: u16,
+}
+
+impl Header {
+    pub const SIZE: usize = 36;
+
+    // Header for compiled code targeting a version
+    pub fn build(version: u8, code: &[u8], var_slots: u16) -> Result<Header, String> {
+        let opcodes = program_info(code)?.opcodes;
+        let header = Header { version, opcodes, var_slots };
+        header.check_with(&Header::supported(version)?)?;
+        Ok(header)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![MAGIC, self.version];
+        out.extend(self.opcodes.0);
+        out.extend(self.var_slots.to_le_bytes());
+        out
+    }
+
+    // Takes the header from the front of buf
+    pub fn decode(buf: &mut &[u8]) -> Result<Header, String> {
+        match buf.first() {
+            Some(&MAGIC) => {},
+            Some(b) => return Err(format!("bad magic: 0x{:02x}", b)),
+            None => return Err("missing header".into()),
+        }
+        if buf.len() < Header::SIZE {
+            return Err("truncated header".into());
+        }
+        let header = Header {
+            version: buf[1],
+            opcodes: OpcodeSet(buf[2..34].try_into().unwrap()),
+            var_slots: u16::from_le_bytes([buf[34], buf[35]]),
+        };
+        *buf = &buf[Header::SIZE..];
+        Ok(header)
+    }
+
+    // Whether this build can run the program
+    pub fn check(&self) -> Result<(), String> {
+        self.check_with(&Header::supported(self.version)?)
+    }
+
+    // Whether the header matches the code it was decoded from
+    pub fn verify(&self, code: &[u8]) -> Result<ProgramInfo, String> {
+        let info = program_info(code)?;
+        if info.opcodes != self.opcodes {
+            return Err(format!("opcodes don't match the code: {:02x?}", info.opcodes.iter().collect::<Vec<_>>()));
+        }
+        if info.var_slots > self.var_slots {
+            return Err(format!("code uses {} var slots, header has {}", info.var_slots, self.var_slots));
+        }
+        Ok(info)
+    }
+
+    fn supported(version: u8) -> Result<OpcodeSet, String> {
+        if version == 0 || version > VERSION {
+            return Err(format!("unsupported version: {}", version));
+        }
+        Ok(OpcodeSet::version(version))
+    }
+
+    fn check_with(&self, supported: &OpcodeSet) -> Result<(), String> {
+        let missing = self.opcodes.missing(supported);
+        if !missing.is_empty() {
+            return Err(format!("unsupported opcodes: {:02x?}", missing));
+        }
+        if self.var_slots as usize > MAX_VARS {
+            return Err(format!("too many var slots: {}", self.var_slots));
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        assert!(OP::try_from(MAGIC).is_err());
+        assert!(OpcodeSet::version(VERSION).contains(OP::TRY(Default::default()).into()));
+        assert!(!OpcodeSet::version(VERSION).contains(0));
+        assert!(OpcodeSet::version(2).contains(OP::RESOURCE_GET(Default::default()).into()));
+        assert!(!OpcodeSet::version(1).contains(OP::RESOURCE_GET(Default::default()).into()));
+
+        let code = [OP::NOT(Default::default()).into(), OP::VAR(Default::default()).into(), 0, 0];
+        let header = Header::build(VERSION, &code, 1).unwrap();
+        assert_eq!(header.opcodes.iter().collect::<Vec<_>>(), vec![0x06, 0x60]);
+
+        let bin = [header.encode(), code.to_vec()].concat();
+        let mut buf = bin.as_ref();
+        assert_eq!(Header::decode(&mut buf), Ok(header.clone()));
+        assert_eq!(buf, code);
+        assert_eq!(header.check(), Ok(()));
+
+        assert_eq!(Header::decode(&mut code.as_ref()), Err("bad magic: 0x06".into()));
+        assert_eq!(Header::build(VERSION + 1, &code, 1), Err(format!("unsupported version: {}", VERSION + 1)));
+        assert_eq!(Header::build(VERSION, &code, MAX_VARS as u16 + 1), Err("too many var slots: 101".into()));
+
+        let mut future = header.clone();
+        future.opcodes.insert(0x01);
+        assert_eq!(future.check(), Err("unsupported opcodes: [01]".into()));
+
+        // The header must match the code
+        assert!(header.verify(&code).is_ok());
+        let mut other = header.clone();
+        other.opcodes = OpcodeSet::version(VERSION);
+        assert!(other.verify(&code).unwrap_err().starts_with("opcodes don't match the code"));
+        let few = Header { var_slots: 0, ..header.clone() };
+        assert_eq!(few.verify(&code), Err("code uses 1 var slots, header has 0".into()));
+        assert!(header.verify(&code[..3]).is_err());
+    }
+}
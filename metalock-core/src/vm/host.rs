@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use crate::types::core::*;
+use crate::types::data::*;
+use crate::types::schema::*;
+use crate::types::tags::*;
+use super::expr::RR;
+
+
+/*
+ * Host functions let integrators expose their own native functions to
+ * programs without adding opcodes. A HostFn is declared once and shared
+ * between the program (which calls it via HOSTCALL) and the host (which
+ * registers an implementation in the EvaluatorContext).
+ *
+ *   const PRICE: HostFn<(Pubkey,), u64> = HostFn::new(1);
+ *
+ * HOSTCALL carries the signature the program was built against, so a
+ * program is refused at load if the host registered the function with
+ * another one. Args and results are checked against the signature too.
+ */
+
+pub type HostResult = std::result::Result<RD, String>;
+pub type HostFnImpl = Rc<dyn Fn(&[RD]) -> HostResult>;
+
+
+#[derive(Debug)]
+pub struct HostFn<I: HostArgs, O: SchemaType>(pub u16, PhantomData<(I, O)>);
+impl<I: HostArgs, O: SchemaType> HostFn<I, O> {
+    pub const fn new(id: u16) -> Self {
+        HostFn(id, PhantomData)
+    }
+    pub fn signature(&self) -> Schema {
+        host_signature(&I::schemas(), &O::to_schema())
+    }
+}
+
+// Function<(inputs), output>
+pub fn host_signature(input: &[Schema], output: &Schema) -> Schema {
+    let items = input.iter().flat_map(|s| s.0.clone()).collect::<Vec<_>>();
+    let mut out = vec![tag::FUNCTION::ID, tag::TUPLE::ID, input.len() as u8];
+    out.extend((items.len() as u16).to_le_bytes());
+    out.extend(items);
+    out.extend(&output.0);
+    Schema(out)
+}
+
+
+pub trait HostArgs: 'static {
+    type Args;
+    fn schemas() -> Vec<Schema>;
+    fn erase(args: Self::Args) -> Vec<RR<()>>;
+}
+
+macro_rules! host_args {
+    ($($t:ident),*) => {
+        impl<$($t: SchemaType),*> HostArgs for ($($t,)*) {
+            type Args = ($(RR<$t>,)*);
+            fn schemas() -> Vec<Schema> {
+                vec![$($t::to_schema()),*]
+            }
+            #[allow(non_snake_case)]
+            fn erase(args: Self::Args) -> Vec<RR<()>> {
+                let ($($t,)*) = args;
+                vec![$(unsafe { std::mem::transmute::<RR<$t>, RR<()>>($t) }),*]
+            }
+        }
+    };
+}
+host_args!();
+host_args!(A);
+host_args!(A, B);
+host_args!(A, B, C);
+host_args!(A, B, C, D);
+
+
+#[derive(Clone)]
+pub struct HostFunction {
+    pub input: Vec<Schema>,
+    pub output: Schema,
+    pub f: HostFnImpl
+}
+
+impl HostFunction {
+    pub fn signature(&self) -> Schema {
+        host_signature(&self.input, &self.output)
+    }
+}
+
+
+#[derive(Clone, Default)]
+pub struct HostFunctions(BTreeMap<u16, HostFunction>);
+impl HostFunctions {
+    pub fn register<I: HostArgs, O: SchemaType, F: Fn(&[RD]) -> HostResult + 'static>(
+        &mut self,
+        decl: &HostFn<I, O>,
+        f: F
+    ) -> &mut Self {
+        let hf = HostFunction { input: I::schemas(), output: O::to_schema(), f: Rc::new(f) };
+        assert!(self.0.insert(decl.0, hf).is_none(), "HostFunctions: {} already registered", decl.0);
+        self
+    }
+    pub fn get(&self, id: u16) -> Option<&HostFunction> {
+        self.0.get(&id)
+    }
+    // Fails if a function is registered with another signature than the
+    // program's. Functions that aren't registered fail when called.
+    pub fn check(&self, id: u16, signature: &Schema) -> Result<(), String> {
+        match self.get(id).map(|hf| hf.signature()) {
+            Some(expected) if expected != *signature => {
+                Err(format!("HOSTCALL {}: expected {}, program has {}", id, expected, signature))
+            },
+            _ => Ok(())
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use solana_program::{account_info::AccountInfo, entrypoint::ProgramResult, instruction::Instruction, pubkey::Pubkey};
+use solana_program::program::{get_return_data, invoke_signed_unchecked};
+
+
+/*
+ * Invoker performs the CPI for INVOKE_SIGNED / GET_INVOKE_RETURN.
+ *
+ * On chain this is SolanaInvoker; off chain a mock can be provided
+ * through the EvaluatorContext.
+ */
+pub trait Invoker {
+    fn invoke_signed(
+        &self,
+        ix: &Instruction,
+        accounts: &[AccountInfo<'static>],
+        signer_seeds: &[&[&[u8]]]
+    ) -> ProgramResult;
+    fn get_return_data(&self) -> Option<(Pubkey, Vec<u8>)>;
+}
+
+
+pub struct SolanaInvoker;
+impl Invoker for SolanaInvoker {
+    fn invoke_signed(
+        &self,
+        ix: &Instruction,
+        accounts: &[AccountInfo<'static>],
+        signer_seeds: &[&[&[u8]]]
+    ) -> ProgramResult {
+        invoke_signed_unchecked(ix, accounts, signer_seeds)
+    }
+    fn get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+        get_return_data()
+    }
+}
@@ -1,5 +1,12 @@
 
 pub mod eval;
 pub mod expr;
+pub mod header;
+pub mod host;
+#[cfg(feature = "anchor")]
+pub mod invoke;
 mod native;
+pub mod resource;
+pub mod sig;
+pub mod verify;
 
@@ -3,6 +3,7 @@ use crate::types::core::*;
 use crate::types::native::*;
 use crate::types::data::*;
 use crate::types::tags::*;
+use super::eval::EvalError;
 
 
 
@@ -22,19 +23,36 @@ impl Native {
         Native(S::to_schema(), s as *const u8)
     }
 
-    pub fn get_struct_field(&self, field_idx: u8, offset: u32) -> RD {
+    // Size of a struct of fixed size fields only, which can be viewed in
+    // account data, see NativePod
+    pub fn pod_size(schema: &Schema) -> Option<usize> {
+        match schema.0.as_slice() {
+            [tag::RSTRUCT::ID, a, b, n, fields @ ..] if fields.len() == *n as usize => {
+                let plain = fields.iter().all(|t| matches!(*t, tag::U8::ID..=tag::BOOL::ID | tag::BUF32::ID));
+                plain.then_some(u16::from_le_bytes([*a, *b]) as usize)
+            },
+            _ => None
+        }
+    }
+
+    pub fn get_struct_field(&self, field_idx: u8, offset: u32) -> Result<RD, EvalError> {
         let (_size, _, mut fields) = self.0.parser().rstruct();
         fields.skip_schema(field_idx as usize);
         let p = self.1 as *const u8;
         let p = unsafe { p.add(offset as usize) };
-        match fields[0] {
-            tag::BOOL::ID => RD::Bool(unsafe { *(p as *const _) }),
-            tag::U32::ID => RD::U32(unsafe { *(p as *const _) }),
-            tag::BUF32::ID => unsafe { *(p as *const [u8; 32]) }.into(),
+        // The struct may be in account data, so unaligned, and bools any byte
+        Ok(match fields[0] {
+            tag::U8::ID => RD::U8(unsafe { *p }),
+            tag::U16::ID => RD::U16(unsafe { (p as *const u16).read_unaligned() }),
+            tag::U32::ID => RD::U32(unsafe { (p as *const u32).read_unaligned() }),
+            tag::U64::ID => unsafe { (p as *const u64).read_unaligned() }.into(),
+            tag::U128::ID => unsafe { (p as *const u128).read_unaligned() }.into(),
+            tag::BOOL::ID => RD::Bool(unsafe { *p } != 0),
+            tag::BUF32::ID => unsafe { (p as *const [u8; 32]).read_unaligned() }.into(),
             tag::LIST::ID => Native(fields.seal(), p).into(), // probably wrong
             tag::BUFFER::ID => unsafe { &*(p as *const Buffer) }.clone().into(),
-            _ => panic!("GET_STRUCT_FIELD: Ptr")
-        }
+            t => return Err(EvalError::Failed(format!("GET_STRUCT_FIELD: unsupported field type {}", t)))
+        })
     }
 
     pub fn set_struct_field(&self, offset: u32, val: RD) -> Native {
@@ -91,6 +109,11 @@ impl Native {
         })
     }
 
+    // Number of items in a native list
+    pub(crate) fn len(&self) -> usize {
+        unsafe { &*(self.1 as *const Vec<u8>) }.len()
+    }
+
     pub fn slice(&self, idx: usize) -> Native {
         let parser = self.0.parser().list();
         let v = unsafe { &*(self.1 as *const Vec<u8>)};
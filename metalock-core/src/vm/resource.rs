@@ -0,0 +1,15 @@
+use crate::types::core::Schema;
+
+
+/*
+ * Named values keyed by schema, kept by the host (ie the Metalock resource
+ * store in the host account). Programs read them with RESOURCE_GET and, if
+ * the EvaluatorContext allows, write them with RESOURCE_SET. Data is encoded
+ * according to the schema.
+ *
+ * The context holds an Rc<RefCell<..>>, so the host keeps access to it.
+ */
+pub trait ResourceStore {
+    fn get(&self, schema: &Schema, name: &str) -> Option<Vec<u8>>;
+    fn set(&mut self, schema: &Schema, name: &str, data: Vec<u8>) -> Result<(), String>;
+}
@@ -0,0 +1,106 @@
+use solana_program::{account_info::AccountInfo, pubkey::Pubkey};
+
+
+/*
+ * Signature verification.
+ *
+ * There is no ed25519 syscall, so on chain a signature is accepted if the
+ * transaction contains an Ed25519 precompile instruction over the same
+ * (pubkey, message, signature); the runtime has already verified it. For
+ * this, the instructions sysvar must be passed in EvaluatorContext.accounts.
+ * Off chain the signature is verified with ed25519-dalek.
+ *
+ * secp256k1 recover is a syscall on chain and libsecp256k1 off chain, both
+ * through solana_program.
+ */
+
+#[cfg(not(target_os = "solana"))]
+pub fn ed25519_verify(_accounts: &[AccountInfo], pubkey: &Pubkey, message: &[u8], signature: &[u8]) -> bool {
+    use ed25519_dalek::{PublicKey, Signature};
+    match (PublicKey::from_bytes(pubkey.as_ref()), Signature::from_bytes(signature)) {
+        (Ok(pk), Ok(sig)) => pk.verify_strict(message, &sig).is_ok(),
+        _ => false
+    }
+}
+
+#[cfg(target_os = "solana")]
+pub fn ed25519_verify(accounts: &[AccountInfo], pubkey: &Pubkey, message: &[u8], signature: &[u8]) -> bool {
+    use solana_program::{ed25519_program, sysvar::instructions};
+    let Some(sysvar) = accounts.iter().find(|a| instructions::check_id(a.key)) else {
+        return false;
+    };
+    (0..)
+        .map_while(|i| instructions::load_instruction_at_checked(i, sysvar).ok())
+        .filter(|ix| ed25519_program::check_id(&ix.program_id))
+        .any(|ix| ed25519_ix_contains(&ix.data, pubkey, message, signature))
+}
+
+/*
+ * Ed25519 precompile instruction data is a u8 count, a padding byte, then
+ * for each signature 7 u16s: signature offset, instruction index, pubkey
+ * offset, instruction index, message offset, message size, instruction index.
+ *
+ * Only data contained in the precompile instruction itself (index u16::MAX)
+ * is matched.
+ */
+#[cfg_attr(not(target_os = "solana"), allow(dead_code))]
+pub(crate) fn ed25519_ix_contains(data: &[u8], pubkey: &Pubkey, message: &[u8], signature: &[u8]) -> bool {
+    let n = data.first().cloned().unwrap_or(0) as usize;
+    let get = |off: usize, len: usize| data.get(off..off + len);
+    let field = |i: usize, f: usize| get(2 + i * 14 + f * 2, 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+
+    (0..n).any(|i| {
+        let matches = || -> Option<bool> {
+            let fields = (0..7).map(|f| field(i, f)).collect::<Option<Vec<u16>>>()?;
+            if [fields[1], fields[3], fields[6]].iter().any(|ix| *ix != u16::MAX) {
+                return Some(false);
+            }
+            let (sig_off, pk_off, msg_off, msg_len) = (fields[0], fields[2], fields[4], fields[5]);
+            Some(
+                get(sig_off as usize, 64)? == signature &&
+                get(pk_off as usize, 32)? == pubkey.as_ref() &&
+                get(msg_off as usize, msg_len as usize)? == message
+            )
+        };
+        matches().unwrap_or(false)
+    })
+}
+
+
+pub fn secp256k1_recover(hash: &[u8], recovery_id: u8, signature: &[u8]) -> Option<[u8; 64]> {
+    solana_program::secp256k1_recover::secp256k1_recover(hash, recovery_id, signature)
+        .ok()
+        .map(|pk| pk.to_bytes())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn precompile_data(pubkey: &Pubkey, message: &[u8], signature: &[u8], ix_index: u16) -> Vec<u8> {
+        let (pk_off, sig_off, msg_off) = (16u16, 48u16, 112u16);
+        let offsets = [sig_off, ix_index, pk_off, ix_index, msg_off, message.len() as u16, ix_index];
+        let mut data = vec![1, 0];
+        offsets.iter().for_each(|o| data.extend(o.to_le_bytes()));
+        data.extend(pubkey.as_ref());
+        data.extend(signature);
+        data.extend(message);
+        data
+    }
+
+    #[test]
+    fn test_ed25519_ix_contains() {
+        let pubkey = Pubkey::new_from_array([1; 32]);
+        let data = precompile_data(&pubkey, b"hello", &[2; 64], u16::MAX);
+        assert!(ed25519_ix_contains(&data, &pubkey, b"hello", &[2; 64]));
+        assert!(!ed25519_ix_contains(&data, &pubkey, b"hellO", &[2; 64]));
+        assert!(!ed25519_ix_contains(&data, &pubkey, b"hello", &[3; 64]));
+        assert!(!ed25519_ix_contains(&data, &Pubkey::default(), b"hello", &[2; 64]));
+        assert!(!ed25519_ix_contains(&data[..50], &pubkey, b"hello", &[2; 64]));
+
+        // data in another instruction is not supported
+        let data = precompile_data(&pubkey, b"hello", &[2; 64], 0);
+        assert!(!ed25519_ix_contains(&data, &pubkey, b"hello", &[2; 64]));
+    }
+}
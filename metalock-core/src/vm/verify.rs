@@ -0,0 +1,402 @@
+
+use crate::types::core::*;
+use crate::types::data::*;
+use crate::types::tags::*;
+use crate::types::tlist::*;
+use super::eval::MAX_VARS;
+use super::expr::*;
+use super::header::OpcodeSet;
+
+
+/*
+ * Structural verification of bytecode that doesn't come from the running
+ * program, ie, function values passed in as inputs. Every opcode must be
+ * valid, operands in bounds and length prefixes must match what they prefix,
+ * so evaluation can't leave the buffer or be steered into data.
+ *
+ * FETCH is rejected, since its offsets point into the program that compiled
+ * the function; function values should be compiled without deduplication.
+ * So are privileged opcodes (OP::is_privileged): a function value can't
+ * call the host, write resources, invoke, or read native structs.
+ *
+ * Compiled programs are walked the same way, with FETCH allowed, to find
+ * the opcodes and var slots they use for the header.
+ */
+
+const MAX_DEPTH: u16 = 64;
+
+pub fn verify_function(f: &EncodedFunction) -> Result<(), String> {
+    if f.0 as usize >= MAX_VARS {
+        return Err(format!("var out of range: {}", f.0));
+    }
+    verify_expr(&f.1)
+}
+
+// The code must be exactly one expression
+pub fn verify_expr(code: &[u8]) -> Result<(), String> {
+    Verifier::new(code, 0).expr_all()
+}
+
+// Length of the schema at the front of buf
+pub fn schema_len(buf: &[u8]) -> Result<usize, String> {
+    let mut schema = buf;
+    Verifier::new(buf, 0).schema(&mut schema)?;
+    Ok(buf.len() - schema.len())
+}
+
+// What a compiled program uses, as recorded in its header
+#[derive(Debug, PartialEq, Eq)]
+pub struct ProgramInfo {
+    pub opcodes: OpcodeSet,
+    // Highest var slot used, plus one
+    pub var_slots: u16,
+    // Function ids and signatures of HOSTCALLs
+    pub host_calls: Vec<(u16, Schema)>,
+}
+
+pub fn program_info(code: &[u8]) -> Result<ProgramInfo, String> {
+    let mut v = Verifier::new(code, 0);
+    v.fetch_limit = Some(code.len());
+    v.expr_all()?;
+    Ok(ProgramInfo { opcodes: v.opcodes, var_slots: v.var_slots, host_calls: v.host_calls })
+}
+
+
+pub(crate) struct Verifier<'a> {
+    buf: &'a [u8],
+    len: usize,
+    depth: u16,
+    // Remaining length once a Skippable operand is read
+    skip_end: Option<usize>,
+    // From PrependSchema, to read a following RD
+    schema: Option<&'a [u8]>,
+    // Program length, if FETCH is allowed
+    fetch_limit: Option<usize>,
+    opcodes: OpcodeSet,
+    var_slots: u16,
+    host_calls: Vec<(u16, Schema)>,
+}
+
+impl<'a> Verifier<'a> {
+    fn new(buf: &'a [u8], depth: u16) -> Verifier<'a> {
+        Verifier { buf, len: buf.len(), depth, skip_end: None, schema: None, fetch_limit: None, opcodes: Default::default(), var_slots: 0, host_calls: vec![] }
+    }
+
+    // A function body within the code
+    fn nested(&mut self, body: &'a [u8]) -> Result<(), String> {
+        let mut v = Verifier::new(body, self.depth);
+        v.fetch_limit = self.fetch_limit;
+        v.expr_all()?;
+        self.opcodes.extend(&v.opcodes);
+        self.var_slots = self.var_slots.max(v.var_slots);
+        self.host_calls.extend(v.host_calls);
+        Ok(())
+    }
+
+    fn var(&mut self) -> Result<(), String> {
+        let var_id = self.u16()?;
+        if var_id as usize >= MAX_VARS {
+            return Err(format!("var out of range: {}", var_id));
+        }
+        self.var_slots = self.var_slots.max(var_id + 1);
+        Ok(())
+    }
+
+    fn offset(&self) -> usize {
+        self.len - self.buf.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if n > self.buf.len() {
+            return Err(format!("unexpected end at {}", self.offset()));
+        }
+        let (a, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(a)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn enter(&mut self) -> Result<(), String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(format!("max depth exceeded at {}", self.offset()));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn expr_all(&mut self) -> Result<(), String> {
+        self.expr()?;
+        if !self.buf.is_empty() {
+            return Err(format!("trailing bytes at {}", self.offset()));
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<(), String> {
+        let offset = self.offset();
+        let byte = self.u8()?;
+        let op = OP::try_from(byte).map_err(|_| format!("invalid opcode 0x{:02x} at {}", byte, offset))?;
+        match op {
+            OP::FETCH() if self.fetch_limit.is_some() => {
+                self.opcodes.insert(byte);
+                if self.u16()? as usize >= self.fetch_limit.unwrap() {
+                    return Err(format!("FETCH out of range at {}", offset));
+                }
+                return Ok(());
+            },
+            OP::NEVER(_) | OP::FETCH() => {
+                return Err(format!("opcode 0x{:02x} not allowed at {}", byte, offset));
+            },
+            _ if op.is_privileged() && self.fetch_limit.is_none() => {
+                return Err(format!("opcode 0x{:02x} not allowed in a function value at {}", byte, offset));
+            },
+            _ => self.opcodes.insert(byte),
+        }
+        self.enter()?;
+        let operands = self.buf;
+        op.verify_operands(self)?;
+        if let OP::HOSTCALL(_) = op {
+            // Function id and signature, see HostCall
+            let n = u16::from_le_bytes([operands[2], operands[3]]) as usize;
+            let id = u16::from_le_bytes([operands[0], operands[1]]);
+            self.host_calls.push((id, Schema(operands[4..4+n].to_vec())));
+        }
+        self.depth -= 1;
+        Ok(())
+    }
+
+    // Skip over one schema
+    fn schema(&mut self, schema: &mut &'a [u8]) -> Result<(), String> {
+        self.enter()?;
+        let mut s = Verifier::new(schema, self.depth);
+        match s.u8()? {
+            tag::OPTION::ID | tag::LIST::ID => {
+                self.schema(&mut s.buf)?;
+            },
+            tag::TUPLE::ID => {
+                s.u8()?;
+                let len = s.u16()?;
+                s.take(len as usize)?;
+            },
+            tag::FUNCTION::ID => {
+                self.schema(&mut s.buf)?;
+                self.schema(&mut s.buf)?;
+            },
+            tag::UNIT::ID..=tag::BUF32::ID => {},
+            // Native structs, which only the running program can view
+            tag::RSTRUCT::ID if self.fetch_limit.is_some() => {
+                s.u16()?;
+                for _ in 0..s.u8()? {
+                    self.schema(&mut s.buf)?;
+                }
+            },
+            t => return Err(format!("unsupported schema tag {}", t)),
+        }
+        *schema = s.buf;
+        self.depth -= 1;
+        Ok(())
+    }
+
+    // Data according to a schema, as read by data_parse
+    fn data(&mut self, schema: &mut &'a [u8]) -> Result<(), String> {
+        self.enter()?;
+        let item = *schema;
+        self.schema(schema)?;
+        let mut s = &item[1..];
+        match item[0] {
+            tag::UNIT::ID => {},
+            tag::U8::ID | tag::BOOL::ID => { self.take(1)?; },
+            tag::U16::ID => { self.take(2)?; },
+            tag::U32::ID => { self.take(4)?; },
+            tag::U64::ID => { self.take(8)?; },
+            tag::U128::ID => { self.take(16)?; },
+            tag::BUF32::ID => { self.take(32)?; },
+            tag::BUFFER::ID => {
+                let n = self.u16()?;
+                self.take(n as usize)?;
+            },
+            tag::STRING::ID => {
+                let n = self.u16()?;
+                let offset = self.offset();
+                std::str::from_utf8(self.take(n as usize)?)
+                    .map_err(|_| format!("invalid utf8 at {}", offset))?;
+            },
+            tag::OPTION::ID => {
+                if self.u8()? > 0 {
+                    self.data(&mut s)?;
+                }
+            },
+            tag::LIST::ID => {
+                for _ in 0..self.u16()? {
+                    let mut item = s;
+                    self.data(&mut item)?;
+                }
+            },
+            tag::TUPLE::ID => {
+                let len = u16::from_le_bytes([s[1], s[2]]) as usize;
+                let mut items = &s[3..3+len];
+                for _ in 0..s[0] {
+                    self.data(&mut items)?;
+                }
+            },
+            tag::FUNCTION::ID => {
+                self.var()?;
+                let n = self.u16()?;
+                let body = self.take(n as usize)?;
+                self.nested(body)?;
+            },
+            t => return Err(format!("unsupported schema tag {}", t)),
+        }
+        self.depth -= 1;
+        Ok(())
+    }
+}
+
+
+pub(crate) trait VerifyOp {
+    fn verify(v: &mut Verifier) -> Result<(), String>;
+}
+// NEVER and FETCH
+impl VerifyOp for () {
+    fn verify(_v: &mut Verifier) -> Result<(), String> {
+        Err("opcode not allowed".into())
+    }
+}
+impl<P: HasParser> VerifyOp for P where P::R: VerifyFields {
+    fn verify(v: &mut Verifier) -> Result<(), String> {
+        P::R::verify(v)
+    }
+}
+
+pub(crate) trait VerifyFields {
+    fn verify(v: &mut Verifier) -> Result<(), String>;
+}
+impl VerifyFields for () {
+    fn verify(_v: &mut Verifier) -> Result<(), String> {
+        Ok(())
+    }
+}
+impl<H: VerifyField, T: TList + VerifyFields> VerifyFields for TCons<H, T> {
+    fn verify(v: &mut Verifier) -> Result<(), String> {
+        let skip_end = v.skip_end.take();
+        H::verify(v)?;
+        if skip_end.is_some_and(|end| end != v.buf.len()) {
+            return Err(format!("length prefix mismatch at {}", v.offset()));
+        }
+        T::verify(v)
+    }
+}
+
+pub(crate) trait VerifyField {
+    fn verify(v: &mut Verifier) -> Result<(), String>;
+}
+macro_rules! verify_field {
+    ($t:ty, |$v:ident| $e:expr) => {
+        impl VerifyField for $t {
+            fn verify($v: &mut Verifier) -> Result<(), String> { $e }
+        }
+    };
+}
+verify_field!(RR<()>, |v| v.expr());
+verify_field!(RR<EncodedFunction>, |v| v.expr());
+verify_field!(Vec<RR<()>>, |v| {
+    for _ in 0..v.u8()? {
+        v.expr()?;
+    }
+    Ok(())
+});
+verify_field!(Skippable, |v| {
+    let n = v.u16()? as usize;
+    if n > v.buf.len() {
+        return Err(format!("length prefix out of range at {}", v.offset()));
+    }
+    v.skip_end = Some(v.buf.len() - n);
+    Ok(())
+});
+verify_field!(VarId<()>, |v| v.var());
+verify_field!(PrependSchema<()>, |v| {
+    let n = v.u16()?;
+    let schema = v.take(n as usize)?;
+    v.schema(&mut { schema })?;
+    v.schema = Some(schema);
+    Ok(())
+});
+verify_field!(Schema, |v| {
+    let n = v.u16()?;
+    let mut schema = v.take(n as usize)?;
+    v.schema(&mut schema)?;
+    if !schema.is_empty() {
+        return Err(format!("trailing schema at {}", v.offset()));
+    }
+    Ok(())
+});
+verify_field!(RD, |v| {
+    let mut schema = v.schema.take().ok_or("data without schema")?;
+    v.data(&mut schema)?;
+    if !schema.is_empty() {
+        return Err(format!("trailing schema at {}", v.offset()));
+    }
+    Ok(())
+});
+verify_field!(String, |v| {
+    let n = v.u16()?;
+    let offset = v.offset();
+    std::str::from_utf8(v.take(n as usize)?).map_err(|_| format!("invalid utf8 at {}", offset))?;
+    Ok(())
+});
+verify_field!(u8, |v| v.take(1).map(|_| ()));
+verify_field!(u16, |v| v.take(2).map(|_| ()));
+verify_field!(u32, |v| v.take(4).map(|_| ()));
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::encode::*;
+
+    #[test]
+    fn test_verify_expr() {
+        let val = |rd: RD, schema: Vec<u8>| {
+            let mut out = vec![OP::VAL(Default::default()).into()];
+            out.extend((schema.len() as u16).rd_encode());
+            out.extend(schema);
+            out.extend(rd.rd_encode());
+            out
+        };
+        let one = val(RD::U16(1), vec![tag::U16::ID]);
+        assert_eq!(verify_expr(&one), Ok(()));
+
+        let mut eq = vec![OP::EQ(Default::default()).into()];
+        eq.extend(&one);
+        eq.extend(&one);
+        assert_eq!(verify_expr(&eq), Ok(()));
+        assert!(verify_expr(&eq[..eq.len()-1]).unwrap_err().starts_with("unexpected end"));
+        assert!(verify_expr(&[eq.clone(), vec![0]].concat()).unwrap_err().starts_with("trailing bytes"));
+
+        assert_eq!(verify_expr(&[0xff]), Err("invalid opcode 0xff at 0".into()));
+        assert_eq!(verify_expr(&[OP::FETCH().into(), 0, 0]), Err("opcode 0x03 not allowed at 0".into()));
+
+        // Skippable length must match the operand
+        let mut and = vec![OP::AND(Default::default()).into()];
+        and.extend(val(RD::Bool(true), vec![tag::BOOL::ID]));
+        let b = val(RD::Bool(true), vec![tag::BOOL::ID]);
+        and.extend((b.len() as u16 + 1).rd_encode());
+        and.extend(&b);
+        and.push(0);
+        assert!(verify_expr(&and).unwrap_err().starts_with("length prefix mismatch"));
+
+        // Function bodies inside data are verified too
+        let f = EncodedFunction(1, vec![0xff]);
+        let fval = val(f.into(), vec![tag::FUNCTION::ID, tag::U8::ID, tag::U8::ID]);
+        assert!(verify_expr(&fval).unwrap_err().starts_with("invalid opcode"));
+        assert!(verify_function(&EncodedFunction(MAX_VARS as u16, one)).is_err());
+    }
+}
@@ -1,226 +1,244 @@
 
-mod types;
-
-
 use std::marker::PhantomData;
 
 use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
+
+use metalock_core::internal::*;
+use metalock_core::vm::eval::{EvalError, EvaluatorContext};
+use metalock_core::impl_deref;
+
+use crate::frontend::{HasHooks, HookError, Metalock, MetalockHooks, ResourceError};
 
-use crate::{eval::{Evaluator, EvaluatorContext}, expr::Function, newval::{schema_is_superset, SchemaParser, SchemaType}, program::Program, schema::*, types::*};
 
-use crate::encode::Encode;
+/*
+ * Anchor integration. A program keeps its hooks in a HooksAccount and calls
+ * the instruction handlers below from its #[program] module. Anchor wants
+ * the Accounts structs at the root of the program crate, so the program
+ * declares those itself; tests/anchor.rs has an example program.
+ */
 
 
-#[derive(Clone, AnchorSerialize, AnchorDeserialize)]
-pub struct MetalockTest {
-    hooks: MetalockHooks,
-    //resources: MetalockResources
+// MetalockHooks in an account owned by the program P, ie
+// Account<'info, HooksAccount<crate::program::MyProgram>>
+pub struct HooksAccount<P> {
+    pub hooks: MetalockHooks,
+    _p: PhantomData<P>
 }
 
-impl HasMetalock for MetalockTest {
+impl_deref!([P], HooksAccount<P> => MetalockHooks, hooks);
+
+impl<P> HasHooks for HooksAccount<P> {
     fn get_hooks(&self) -> &MetalockHooks { &self.hooks }
     fn get_hooks_mut(&mut self) -> &mut MetalockHooks { &mut self.hooks }
-    //fn get_resources(&self) -> &MetalockResources { &self.resources }
-    //fn get_resources_mut(&mut self) -> &mut MetalockResources { &mut self.resources }
-}
-
-#[derive(Clone, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, PartialOrd, Ord)]
-pub struct MetalockHook {
-    schema: Schema,
-    name: String,
-    code: Vec<u8>
-}
-
-#[derive(Clone, AnchorSerialize, AnchorDeserialize)]
-pub struct MetalockHooks(Vec<MetalockHook>);
-impl_deref!([], MetalockHooks, Vec<MetalockHook>, 0);
-pub type MetalockResources = Vec<(Schema, Vec<(String, ResourceDataOrPtr)>)>;
-
-pub trait HasMetalock {
-    fn get_hooks(&self) -> &MetalockHooks;
-    fn get_hooks_mut(&mut self) -> &mut MetalockHooks;
-    //fn get_resources(&self) -> &MetalockResources;
-    //fn get_resources_mut(&mut self) -> &mut MetalockResources;
-}
-
-pub struct Metalock<'a, S: HasMetalock>(pub &'a mut S);
-
-//impl<'a, S: HasMetalock> Metalock<'a, S> {
-//    pub fn get_typed<T: SchemaType + Decode>(&self, name: String) -> Option<T> {
-//        let typ = T::to_schema();
-//        self._get(&ResourceId(typ, name)).map(
-//            |b| Decode::rd_decode(&mut &**b).expect("Could not deserialize")
-//        )
-//    }
-//    pub fn set_typed<T: SchemaType + Encode>(&mut self, name: String, data: T) {
-//        let t = T::to_schema();
-//        let d = data.rd_encode();
-//        self._set(ResourceId(t, name), ResourceDataOrPtr::Data(d), Self::SET_INSERT);
-//    }
-//    pub fn set_with_schema(&mut self, schema: &Schema, name: String, data: RD) {
-//        panic!("set_with_schema")
-//        //assert!(validate_resource_data(&"", schema, &data).is_ok(), "Resources::set: invalid data");
-//        //self._set(ResourceId(schema.encode(), name), data.into(), Self::SET_INSERT | Self::SET_UPDATE);
-//    }
-//
-//    const SET_INSERT: u8 = 1;
-//    const SET_UPDATE: u8 = 2;
-//
-//    fn _set(&mut self, ResourceId(typ, name): ResourceId, data: ResourceDataOrPtr, op: u8) -> SetResult {
-//        if let Some((_, resources)) = self.0.get_resources_mut().iter_mut().find(|r| r.0 == typ) {
-//            if let Some(v) = resources.iter_mut().find(|f| f.0 == name) {
-//                if op & 1 > 0 {
-//                    return SetResult::Replaced(std::mem::replace(&mut v.1, data));
-//                }
-//            }
-//            if op & 2 > 0 {    
-//                resources.push((name, data.into()));
-//                return SetResult::Inserted;
-//            }
-//        } else {
-//            if op & 2 > 0 {    
-//                self.0.get_resources_mut().push((typ, vec![(name, data.into())]));
-//                return SetResult::Inserted;
-//            }
-//        }
-//        SetResult::Noop
-//    }
-//    //pub fn take(&mut self, ResourceId(typ, name): ResourceId) -> Option<ResourceData> {
-//    //    if let Some(idxa) = self.get_resources().iter().position(|r| r.0 == typ) {
-//    //        let d = &mut self.get_resources()[idxa].1;
-//    //        if let Some(idxb) = d.iter().position(|f| f.0 == name) {
-//    //            let o = Some(d.remove(idxb).1);
-//    //            if d.len() == 0 {
-//    //                self.get_resources().remove(idxa);
-//    //            }
-//    //            return o;
-//    //        }
-//    //    }
-//    //    None
-//    //}
-//    //
-//
-//    /*
-//     * Upgrade allows additional fields to be added to a schema,
-//     * for example, if you have the schema:
-//     *
-//     * { members: [{ name: String, age: u8 }] }
-//     *
-//     * You can upgrade it to a compatible schema:
-//     *   
-//     * { members: [{ name: String, age: u8, balance: u64 }] }
-//     *
-//     * And the old key will point to the new key for backwards compatability
-//     */
-//    //pub fn upgrade(&mut self, old_id: ResourceId, new_id: ResourceId) {
-//    //    assert!(
-//    //        schema_is_superset(&old_id.0.to_schema(), &new_id.0.to_schema()),
-//    //        "Resources::upgrade: not superset"
-//    //    );
-//
-//    //    let ptr = ResourceDataOrPtr::Ptr(new_id.clone());
-//    //    let old = self._set(old_id, ptr, Self::SET_UPDATE);
-//    //    let old = match old {
-//    //        SetResult::Replaced(d) => d,
-//    //        _ => panic!("Resources::upgrade: old does not exist")
-//    //    };
-//
-//    //    let r = self._set(new_id, old, Self::SET_INSERT);
-//    //    assert!(r == SetResult::Inserted, "Resources::upgrade: new already exists");
-//    //}
-//
-//    fn _get(&self, id: &ResourceId) -> Option<&Vec<u8>> {
-//        let r = &self.0.get_resources().iter().find(|r| r.0 == id.0)?.1.iter().find(|f| f.0 == id.1)?.1;
-//        match r {
-//            ResourceDataOrPtr::Data(d) => Some(d),
-//            ResourceDataOrPtr::Ptr(p) => self._get(p)
-//        }
-//    }
-//}
-
-
-
-#[derive(Clone, PartialEq, Eq, AnchorSerialize, AnchorDeserialize)]
-pub struct ResourceId(pub Schema, pub String);
-
-#[derive(Clone, PartialEq, Eq, AnchorSerialize, AnchorDeserialize)]
-pub enum ResourceDataOrPtr {
-    Data(Vec<u8>),
-    Ptr(ResourceId)
-}
-
-impl_into!([], ResourceDataOrPtr, ResourceData, |self| ResourceDataOrPtr::Data(self.rd_encode()));
-impl_into!([], ResourceDataOrPtr, ResourceId, |self| ResourceDataOrPtr::Ptr(self));
-
-#[derive(PartialEq, Eq)]
-enum SetResult {
-    Replaced(ResourceDataOrPtr),
-    Inserted,
-    Noop
-}
-
-
-
-
-use crate::schema::tag::{self, TagType};
+}
+
+// Derived Clone and Default would require them of P
+impl<P> Clone for HooksAccount<P> {
+    fn clone(&self) -> Self {
+        HooksAccount { hooks: self.hooks.clone(), _p: PhantomData }
+    }
+}
+impl<P> Default for HooksAccount<P> {
+    fn default() -> Self {
+        HooksAccount { hooks: Default::default(), _p: PhantomData }
+    }
+}
+
+// As #[account] would derive it, sha256("account:MetalockHooks")[..8]
+impl<P> Discriminator for HooksAccount<P> {
+    const DISCRIMINATOR: [u8; 8] = [117, 97, 176, 193, 98, 100, 206, 167];
+}
+
+impl<P: Id> Owner for HooksAccount<P> {
+    fn owner() -> Pubkey {
+        P::id()
+    }
+}
+
+impl<P> AccountSerialize for HooksAccount<P> {
+    fn try_serialize<W: std::io::Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&Self::DISCRIMINATOR).map_err(|_| ErrorCode::AccountDidNotSerialize)?;
+        self.hooks.serialize(writer).map_err(|_| ErrorCode::AccountDidNotSerialize)?;
+        Ok(())
+    }
+}
+
+impl<P> AccountDeserialize for HooksAccount<P> {
+    fn try_deserialize(buf: &mut &[u8]) -> Result<Self> {
+        if buf.len() < 8 {
+            return err!(ErrorCode::AccountDiscriminatorNotFound);
+        }
+        if buf[..8] != Self::DISCRIMINATOR {
+            return err!(ErrorCode::AccountDiscriminatorMismatch);
+        }
+        Self::try_deserialize_unchecked(buf)
+    }
+
+    // The account may be larger than the hooks, so trailing bytes are allowed
+    fn try_deserialize_unchecked(buf: &mut &[u8]) -> Result<Self> {
+        *buf = buf.get(8..).ok_or(ErrorCode::AccountDidNotDeserialize)?;
+        let hooks = MetalockHooks::deserialize(buf).map_err(|_| ErrorCode::AccountDidNotDeserialize)?;
+        Ok(HooksAccount { hooks, _p: PhantomData })
+    }
+}
+
 
 /*
- * Hooks
+ * Space. Accounts don't grow by themselves; size them for the hooks they
+ * should hold, or realloc before add_hook.
  */
 
-impl<'a, S: HasMetalock> Metalock<'a, S> {
-    pub fn add_hook(&mut self, name: String, bin: Vec<u8>) -> Result<()> {
-
-        // take schema from bin
-        let parser = ParserBuffer::new(&bin);
-        let mut parser = SchemaParser(parser);
-        assert!(parser[0] == tag::FUNCTION::ID);
-        let schema = parser.take_schema();
-        let hook = MetalockHook {
-            name,
-            schema,
-            code: parser.to_vec()
-        };
+// Schema, name and code length prefixes, priority, id, owner and enabled
+pub const HOOK_OVERHEAD: usize = 4 + 4 + 4 + 1 + 32 + 32 + 1;
 
-        let r = self.0.get_hooks().binary_search(&hook);
-        match r {
-            Ok(_) => panic!("Hook exists"),
-            Err(idx) => {
-                self.0.get_hooks_mut().insert(idx, hook);
-                Ok(())
-            }
-        }
+// A hook as stored, bin being the upload passed to add_hook
+pub const fn hook_space(name_len: usize, bin_len: usize) -> usize {
+    HOOK_OVERHEAD + name_len + bin_len
+}
+
+// Without the discriminator, as is the convention for Space
+impl Space for MetalockHooks {
+    // Hooks length prefix and owner quota
+    const INIT_SPACE: usize = 4 + 3;
+}
+
+impl MetalockHooks {
+    // Current serialized length
+    pub fn space(&self) -> usize {
+        let hooks = self.iter().map(|hook| hook_space(hook.name().len(), hook.schema().0.len() + hook.code().len()));
+        MetalockHooks::INIT_SPACE + hooks.sum::<usize>()
+    }
+}
+
+impl<P> HooksAccount<P> {
+    // Account size for n hooks with names and uploads up to the given lengths
+    pub const fn space(n: usize, max_name_len: usize, max_bin_len: usize) -> usize {
+        8 + MetalockHooks::INIT_SPACE + n * hook_space(max_name_len, max_bin_len)
+    }
+}
+
+
+/*
+ * Instruction handlers
+ */
+
+// Fails with AccountFull if the account is too small for the new hook
+pub fn add_hook<P: Id>(hooks: &mut Account<HooksAccount<P>>, owner: &Signer, name: String, bin: Vec<u8>) -> Result<[u8; 32]> {
+    let id = Metalock(&mut **hooks).add_hook(owner.key(), name, bin)?;
+    if 8 + hooks.space() > hooks.to_account_info().data_len() {
+        return err!(MetalockError::AccountFull);
+    }
+    Ok(id)
+}
+
+pub fn remove_hook<P: Id>(hooks: &mut Account<HooksAccount<P>>, owner: &Signer, id: [u8; 32]) -> Result<()> {
+    Metalock(&mut **hooks).remove_hook(&owner.key(), &id)?;
+    Ok(())
+}
+
+// Call a pinned hook
+pub fn call_hook<P: Id, In: SchemaType + Into<RD>, Out: SchemaType + FromRD>(
+    hooks: &mut Account<HooksAccount<P>>,
+    context: EvaluatorContext,
+    id: [u8; 32],
+    input: &In
+) -> Result<Out> {
+    let rd = Metalock(&mut **hooks).call_hook_by_id(context, &id, input, PhantomData::<Out>)?;
+    Ok(rd._as())
+}
+
+// Succeeds if every hook with the name returns true. A hook that fails
+// fails the instruction with its error, one that returns false with
+// HookRejected
+pub fn check_hooks<P: Id, In: SchemaType + Into<RD>>(
+    hooks: &mut Account<HooksAccount<P>>,
+    context: EvaluatorContext,
+    name: &str,
+    input: &In
+) -> Result<()> {
+    let report = Metalock(&mut **hooks).all_hooks(context, name, input);
+    if report.value {
+        return Ok(());
     }
+    match report.runs.into_iter().last().map(|run| run.result) {
+        Some(Err(e)) => Err(HookError::Eval(e).into()),
+        _ => err!(MetalockError::HookRejected)
+    }
+}
+
+
+/*
+ * Errors
+ */
+
+#[error_code]
+pub enum MetalockError {
+    #[msg("Hook upload doesn't start with a function schema")]
+    InvalidSchema,
+    #[msg("Hook program is not supported")]
+    Unsupported,
+    #[msg("Hook exists")]
+    HookExists,
+    #[msg("Hook not found")]
+    HookNotFound,
+    #[msg("Not the hook owner")]
+    NotOwner,
+    #[msg("Hook disabled")]
+    HookDisabled,
+    #[msg("Hook schema mismatch")]
+    SchemaMismatch,
+    #[msg("Hook quota exceeded")]
+    QuotaExceeded,
+    #[msg("Hook evaluation failed")]
+    EvalFailed,
+    #[msg("Hook rejected")]
+    HookRejected,
+    #[msg("Account too small")]
+    AccountFull,
+    #[msg("Resource not found")]
+    ResourceNotFound,
+    #[msg("Resource exists")]
+    ResourceExists,
+    #[msg("Resource schema is not a superset")]
+    NotSuperset,
+    #[msg("Resource was upgraded")]
+    ResourceUpgraded,
+    #[msg("Invalid resource")]
+    InvalidResource,
+}
 
-    pub fn call_hook_with_results<In: SchemaType + Into<RD>, Out: SchemaType>(
-        &self,
-        context: EvaluatorContext,
-        name: String,
-        input: &In,
-        out_p: PhantomData<Out>
-    ) -> Vec<std::result::Result<RD, String>> {
-
-        let schema = Function::<In, Out>::to_schema();
-        
-        let needle = MetalockHook {
-            schema: schema.clone(),
-            name: name.clone(),
-            code: Default::default()
+// Codes from assert_code pass through as custom program errors, the rest
+// are logged and mapped to MetalockError
+impl From<HookError> for anchor_lang::error::Error {
+    fn from(e: HookError) -> Self {
+        let code = match e {
+            HookError::Eval(EvalError::Code(code)) => return ProgramError::Custom(code).into(),
+            HookError::InvalidSchema => MetalockError::InvalidSchema,
+            HookError::Unsupported(_) => MetalockError::Unsupported,
+            HookError::Exists => MetalockError::HookExists,
+            HookError::NotFound => MetalockError::HookNotFound,
+            HookError::NotOwner => MetalockError::NotOwner,
+            HookError::Disabled => MetalockError::HookDisabled,
+            HookError::SchemaMismatch => MetalockError::SchemaMismatch,
+            HookError::QuotaExceeded(_) => MetalockError::QuotaExceeded,
+            HookError::Eval(_) => MetalockError::EvalFailed,
         };
-        let idx = self.0.get_hooks().binary_search(&needle).unwrap_or_else(|e| e);
-
-        let mut results = vec![];
-
-        for hook in &self.0.get_hooks()[idx..] {
-            if hook.schema != schema || hook.name != name {
-                break;
-            } else {
-                let mut eval = Evaluator::new(&mut hook.code.as_ref(), context.clone());
-                let r = eval.run(input.clone().into());
-                results.push(Ok(r));
-            }
-        }
+        msg!("{}", e);
+        code.into()
+    }
+}
 
-        results
+impl From<ResourceError> for anchor_lang::error::Error {
+    fn from(e: ResourceError) -> Self {
+        let code = match e {
+            ResourceError::NotFound => MetalockError::ResourceNotFound,
+            ResourceError::Exists => MetalockError::ResourceExists,
+            ResourceError::NotSuperset => MetalockError::NotSuperset,
+            ResourceError::Upgraded => MetalockError::ResourceUpgraded,
+            ResourceError::Invalid(_) => MetalockError::InvalidResource,
+        };
+        msg!("{}", e);
+        code.into()
     }
 }
@@ -2,12 +2,26 @@
 use std::marker::PhantomData;
 
 use metalock_core::vm::expr::*;
+use metalock_core::vm::host::*;
 use metalock_core::internal::*;
+use solana_program::pubkey::Pubkey;
+#[cfg(feature = "anchor")]
+use solana_program::instruction::AccountMeta;
 
 
 pub fn rr<R: SchemaType, O: Op<R> + 'static>(op: O) -> RR<R> {
     RR::new(op)
 }
+/*
+ * Function bodies refer to outer bindings (function inputs and let_) by var
+ * slot, and see the values bound when the function is called. Passed to a
+ * function value from outside the program, it sees the values bound where
+ * the program called that function value, whenever it calls back. A call
+ * saves and restores the slot it binds, so nested combinators and recursion
+ * don't clobber each other. A function can't outlive the bindings it refers
+ * to, ie, be returned out of the let_ or function that binds them; encoding
+ * fails if the type of the let_ or function allows that.
+ */
 pub fn to_function<B: ToRR<O>, I: SchemaType, O: SchemaType, F: FnOnce(RR<I>) -> B + 'static + Clone>(f: F) -> RR<Function<I, O>> {
     let var = Var::new();
     Function(var.0.clone(), f(var.rr()).rr()).rr()
@@ -16,9 +30,22 @@ pub fn assert(cond: impl ToRR<bool>, s: impl Into<String>) -> RR<()> {
     let s: String = s.into();
     Assert(cond.rr(), s.rr().into()).rr()
 }
+// Fails with EvalError::Code(code), see ErrorTable for messages
+pub fn assert_code(cond: impl ToRR<bool>, code: u32) -> RR<()> {
+    AssertCode(cond.rr(), code).rr()
+}
 pub fn panic(s: impl Into<String>) -> RR<()> {
     Panic(s.into(), PhantomData::default()).rr()
 }
+/*
+ * Some(body), or None if the body fails, ie a panic, an assert or an index
+ * out of range. Var writes in a failed body are rolled back, but side
+ * effects are not: set_resource writes, invokes and host calls made before
+ * the failure have happened. Fail before them, or don't try_ them.
+ */
+pub fn try_<O: SchemaType>(body: impl ToRR<O>) -> RR<Option<O>> {
+    Try(body.rr()).rr()
+}
 
 
 
@@ -40,6 +67,36 @@ rr_impl!(ToRRCommon<I: SchemaType> for ToRR<I> {
     fn bind<O, F>(self, f: F) -> RR<O> where F: FnOnce(RR<I>) -> RR<O> {
         f(self.rr())
     }
+    // Like bind, but evaluates self once
+    fn bind_var<O: SchemaType, F>(self, f: F) -> RR<O> where F: FnOnce(RR<I>) -> RR<O> {
+        let_(self, f)
+    }
+});
+
+
+/*
+ * Evaluate value once and pass a reference to it to f
+ */
+pub fn let_<I: SchemaType, O: SchemaType>(value: impl ToRR<I>, f: impl FnOnce(RR<I>) -> RR<O>) -> RR<O> {
+    let var = Var::new();
+    let body = f(var.rr());
+    rr(Let(var.0, value.rr(), body))
+}
+
+
+rr_impl!(ToRROrd<I: PartialOrd ; SchemaType> for ToRR<I> {
+    fn less_than(self, other: impl ToRR<I>) -> RR<bool> {
+        rr(LessThan(self.rr(), other.rr()))
+    }
+    fn greater_than(self, other: impl ToRR<I>) -> RR<bool> {
+        rr(LessThan(other.rr(), self.rr()))
+    }
+    fn less_than_eq(self, other: impl ToRR<I>) -> RR<bool> {
+        self.greater_than(other).not()
+    }
+    fn greater_than_eq(self, other: impl ToRR<I>) -> RR<bool> {
+        self.less_than(other).not()
+    }
 });
 
 
@@ -47,6 +104,12 @@ rr_impl!(ToRRInt<I: std::ops::Add ; SchemaType> for ToRR<I> {
     fn add(self, other: impl ToRR<I>) -> RR<I> {
         Add(self.rr(), other.rr()).rr()
     }
+    fn sub(self, other: impl ToRR<I>) -> RR<I> where I: std::ops::Sub {
+        Sub(self.rr(), other.rr()).rr()
+    }
+    fn mul(self, other: impl ToRR<I>) -> RR<I> where I: std::ops::Mul {
+        Mul(self.rr(), other.rr()).rr()
+    }
 });
 
 
@@ -73,9 +136,97 @@ rr_impl!(ToRRVec<I: SchemaType> for ToRR<Vec<I>> {
     fn slice(self, idx: impl ToRR<u16>) -> RR<Vec<I>> {
         rr(Slice(self.rr(), idx.rr()))
     }
+    fn filter<F: Fn(RR<I>) -> RR<bool> + Clone + 'static>(self, f: F) -> RR<Vec<I>> {
+        rr(Filter(self.rr(), to_function(f)))
+    }
+    fn fold<A: SchemaType, F: Fn(RR<A>, RR<I>) -> RR<A> + Clone + 'static>(self, init: impl ToRR<A>, f: F) -> RR<A> {
+        rr(Fold(self.rr(), init.rr(), to_function(move |t: RR<(A, I)>| {
+            let (acc, item) = t.unpack();
+            f(acc, item)
+        })))
+    }
+    fn reduce<F: Fn(RR<I>, RR<I>) -> RR<I> + Clone + 'static>(self, f: F) -> RR<Option<I>> {
+        rr(Reduce(self.rr(), to_function(move |t: RR<(I, I)>| {
+            let (acc, item) = t.unpack();
+            f(acc, item)
+        })))
+    }
+    fn find<F: Fn(RR<I>) -> RR<bool> + Clone + 'static>(self, f: F) -> RR<Option<I>> {
+        rr(Find(self.rr(), to_function(f)))
+    }
+    fn position<F: Fn(RR<I>) -> RR<bool> + Clone + 'static>(self, f: F) -> RR<Option<u16>> {
+        rr(Position(self.rr(), to_function(f)))
+    }
+    fn count<F: Fn(RR<I>) -> RR<bool> + Clone + 'static>(self, f: F) -> RR<u16> {
+        rr(Count(self.rr(), to_function(f)))
+    }
+    fn zip<B: SchemaType>(self, other: impl ToRR<Vec<B>>) -> RR<Vec<(I, B)>> {
+        rr(Zip(self.rr(), other.rr()))
+    }
+    fn enumerate(self) -> RR<Vec<(u16, I)>> {
+        rr(Enumerate(self.rr()))
+    }
+    fn concat(self, other: impl ToRR<Vec<I>>) -> RR<Vec<I>> {
+        rr(ListConcat(self.rr(), other.rr()))
+    }
+    fn push(self, item: impl ToRR<I>) -> RR<Vec<I>> {
+        rr(Push(self.rr(), item.rr()))
+    }
+    fn reverse(self) -> RR<Vec<I>> {
+        rr(Reverse(self.rr()))
+    }
+    fn take(self, n: impl ToRR<u16>) -> RR<Vec<I>> {
+        rr(Take(self.rr(), n.rr()))
+    }
+    fn sort(self) -> RR<Vec<I>> where I: PartialOrd {
+        rr(Sort(self.rr()))
+    }
+    fn sort_by_key<K: PartialOrd + SchemaType, F: Fn(RR<I>) -> RR<K> + Clone + 'static>(self, f: F) -> RR<Vec<I>> {
+        rr(SortByKey(self.rr(), to_function(f)))
+    }
+    // Removes consecutive duplicates only, sort first to remove them all
+    fn dedup(self) -> RR<Vec<I>> {
+        rr(Dedup(self.rr()))
+    }
+    #[allow(clippy::wrong_self_convention)]
+    fn is_sorted(self) -> RR<bool> where I: PartialOrd {
+        rr(IsSorted(self.rr()))
+    }
 });
 
 
+rr_impl!(ToRRVecSum<I: std::ops::Add ; SchemaType ; Default ; Into<RD>> for ToRR<Vec<I>> {
+    fn sum(self) -> RR<I> {
+        rr(Sum(self.rr(), I::default().rr()))
+    }
+});
+
+
+pub fn range(n: impl ToRR<u16>) -> RR<Vec<u16>> {
+    rr(Range(n.rr()))
+}
+
+
+/*
+ * Bounded loops
+ */
+pub fn repeat<A: SchemaType, F>(n: impl ToRR<u16>, init: impl ToRR<A>, f: F) -> RR<A>
+    where F: Fn(RR<u16>, RR<A>) -> RR<A> + Clone + 'static
+{
+    rr(Repeat(n.rr(), init.rr(), to_function(move |t: RR<(u16, A)>| {
+        let (i, acc) = t.unpack();
+        f(i, acc)
+    })))
+}
+// Fails (catchable with try_) if cond still holds after max_iterations
+pub fn while_<A: SchemaType, C, F>(max_iterations: impl ToRR<u16>, init: impl ToRR<A>, cond: C, body: F) -> RR<A>
+    where C: Fn(RR<A>) -> RR<bool> + Clone + 'static,
+          F: Fn(RR<A>) -> RR<A> + Clone + 'static
+{
+    rr(While(max_iterations.rr(), init.rr(), to_function(cond), to_function(body)))
+}
+
+
 rr_impl!(ToRRIter<I: SchemaType, It: IntoIterator<Item=I>; SchemaType> for ToRR<It> {
     fn each<B: ToRR<()>, F: Fn(RR<I>) -> B + 'static + Clone>(self, f: F) -> RR<()> {
         rr(Each(self.rr(), to_function(f)))
@@ -90,6 +241,108 @@ rr_impl!(ToRRHasLen<I: HasLen; SchemaType> for ToRR<I> {
 });
 
 
+rr_impl!(ToRRBytes<I: HasBytes ; SchemaType> for ToRR<I> {
+    fn sha256(self) -> RR<[u8; 32]> {
+        rr(Sha256(self.rr()))
+    }
+    fn keccak256(self) -> RR<[u8; 32]> {
+        rr(Keccak256(self.rr()))
+    }
+    fn blake3(self) -> RR<[u8; 32]> {
+        rr(Blake3(self.rr()))
+    }
+    fn concat_bytes<B: HasBytes + SchemaType>(self, other: impl ToRR<B>) -> RR<Buffer> {
+        rr(Concat(self.rr(), other.rr()))
+    }
+    fn starts_with(self, other: impl ToRR<I>) -> RR<bool> {
+        rr(StartsWith(self.rr(), other.rr()))
+    }
+    fn ends_with(self, other: impl ToRR<I>) -> RR<bool> {
+        rr(EndsWith(self.rr(), other.rr()))
+    }
+    fn contains_bytes(self, other: impl ToRR<I>) -> RR<bool> {
+        rr(Contains(self.rr(), other.rr()))
+    }
+    fn byte_at(self, idx: impl ToRR<u16>) -> RR<u8> {
+        rr(ByteAt(self.rr(), idx.rr()))
+    }
+    fn to_buffer(self) -> RR<Buffer> {
+        rr(ToBuffer(self.rr()))
+    }
+    fn read_le<T: LeInt + SchemaType>(self, offset: impl ToRR<u16>) -> RR<T> {
+        rr(FromLeBytes(self.rr(), offset.rr(), T::SIZE, PhantomData))
+    }
+});
+
+
+rr_impl!(ToRRSlice<I: HasSlice ; SchemaType> for ToRR<I> {
+    // Byte offsets, panics if out of bounds or not on a char boundary
+    fn slice_bytes(self, start: impl ToRR<u16>, end: impl ToRR<u16>) -> RR<I> {
+        rr(BytesSlice(self.rr(), start.rr(), end.rr()))
+    }
+});
+
+
+rr_impl!(ToRRString for ToRR<String> {
+    fn concat_str(self, other: impl ToRR<String>) -> RR<String> {
+        rr(StrConcat(self.rr(), other.rr()))
+    }
+});
+
+
+rr_impl!(ToRRBuffer for ToRR<Buffer> {
+    fn utf8(self) -> RR<Option<String>> {
+        rr(Utf8Decode(self.rr()))
+    }
+});
+
+
+rr_impl!(ToRRLeInt<I: LeInt ; SchemaType> for ToRR<I> {
+    fn to_le_bytes(self) -> RR<Buffer> {
+        rr(ToLeBytes(self.rr()))
+    }
+});
+
+/*
+ * Signatures, see metalock_core::vm::sig for the on chain requirements
+ */
+pub fn ed25519_verify<M: HasBytes + SchemaType>(
+    pubkey: impl ToRR<Pubkey>,
+    message: impl ToRR<M>,
+    signature: impl ToRR<Buffer>
+) -> RR<bool> {
+    rr(Ed25519Verify(pubkey.rr(), message.rr(), signature.rr()))
+}
+pub fn secp256k1_recover(
+    hash: impl ToRR<[u8; 32]>,
+    recovery_id: impl ToRR<u8>,
+    signature: impl ToRR<Buffer>
+) -> RR<Option<Buffer>> {
+    rr(Secp256k1Recover(hash.rr(), recovery_id.rr(), signature.rr()))
+}
+
+/*
+ * Verify a Merkle proof where each node is keccak256 of its sorted children
+ */
+pub fn verify_merkle_proof(
+    leaf: impl ToRR<[u8; 32]>,
+    proof: impl ToRR<Vec<[u8; 32]>>,
+    root: impl ToRR<[u8; 32]>
+) -> RR<bool> {
+    let node = Var::new();
+    let n = node.clone();
+    leaf.rr().write(&node)
+        .then(proof.rr().each(move |p| {
+            let a = rr(n.clone());
+            a.clone().less_than(p.clone())
+                .choose(a.clone().concat_bytes(p.clone()), p.concat_bytes(a))
+                .keccak256()
+                .write(&n)
+        }))
+        .then(rr(node).equals(root))
+}
+
+
 rr_impl!(ToRRBool for ToRR<bool> {
     fn choose<A: SchemaType>(&self, a: impl ToRR<A>, b: impl ToRR<A>) -> RR<A> {
         rr(If(self.rr(), a.rr().into(), b.rr().into()))
@@ -126,6 +379,110 @@ rr_impl!(ToRRFunction<I: SchemaType, O: SchemaType> for ToRR<Function<I, O>> {
     }
 });
 
+/*
+ * A recursive function, f gets the function itself and the input. When the
+ * expression is evaluated the function is stored in a var, which the body
+ * reads to recurse.
+ */
+pub fn fix<I: SchemaType, O: SchemaType, F>(f: F) -> RR<Function<I, O>>
+    where F: Fn(RR<Function<I, O>>, RR<I>) -> RR<O> + Clone + 'static
+{
+    let this = Var::<Function<I, O>>::new();
+    let this_rr = rr(this.clone());
+    let func = to_function(move |input| f(this_rr.clone(), input));
+    rr(Seq(rr(SetVar(this.0.clone(), func)), rr(this)))
+}
+
+#[cfg(feature = "anchor")]
+pub fn proxy_call(
+    program_id: impl ToRR<Pubkey>,
+    data: impl ToRR<Buffer>,
+    accounts: impl ToRR<Vec<AccountMeta>>
+) -> RR<MetalockProxyCall> {
+    rr(ProxyCall(program_id.rr(), data.rr(), accounts.rr()))
+}
+#[cfg(feature = "anchor")]
+pub fn get_invoke_return() -> RR<Buffer> {
+    rr(GetInvokeReturn())
+}
+
+#[cfg(feature = "anchor")]
+rr_impl!(ToRRProxyCall for ToRR<MetalockProxyCall> {
+    fn invoke_signed(&self) -> RR<()> {
+        rr(InvokeSigned(self.rr()))
+    }
+});
+
+/*
+ * Accounts provided by the host in EvaluatorContext.accounts
+ */
+pub fn account_key(idx: impl ToRR<u16>) -> RR<Pubkey> {
+    rr(AccountField(idx.rr(), account_field::KEY, PhantomData))
+}
+pub fn account_owner(idx: impl ToRR<u16>) -> RR<Pubkey> {
+    rr(AccountField(idx.rr(), account_field::OWNER, PhantomData))
+}
+pub fn account_lamports(idx: impl ToRR<u16>) -> RR<u64> {
+    rr(AccountField(idx.rr(), account_field::LAMPORTS, PhantomData))
+}
+pub fn account_is_signer(idx: impl ToRR<u16>) -> RR<bool> {
+    rr(AccountField(idx.rr(), account_field::IS_SIGNER, PhantomData))
+}
+pub fn account_is_writable(idx: impl ToRR<u16>) -> RR<bool> {
+    rr(AccountField(idx.rr(), account_field::IS_WRITABLE, PhantomData))
+}
+pub fn account_data(idx: impl ToRR<u16>) -> RR<Buffer> {
+    rr(AccountField(idx.rr(), account_field::DATA, PhantomData))
+}
+pub fn account_data_as<S: NativePod>(idx: impl ToRR<u16>, offset: u16) -> RR<S> {
+    rr(AccountNative(offset, idx.rr(), PhantomData))
+}
+
+/*
+ * Program derived addresses
+ */
+pub fn create_program_address(seeds: impl ToRR<Vec<Buffer>>, program_id: impl ToRR<Pubkey>) -> RR<Option<Pubkey>> {
+    rr(CreateProgramAddress(seeds.rr(), program_id.rr()))
+}
+pub fn find_program_address(seeds: impl ToRR<Vec<Buffer>>, program_id: impl ToRR<Pubkey>) -> RR<(Pubkey, u8)> {
+    rr(FindProgramAddress(seeds.rr(), program_id.rr()))
+}
+
+/*
+ * Sysvars provided by the host in EvaluatorContext.{clock,rent}
+ */
+pub fn clock_slot() -> RR<u64> {
+    rr(ClockField(clock_field::SLOT))
+}
+pub fn clock_epoch() -> RR<u64> {
+    rr(ClockField(clock_field::EPOCH))
+}
+pub fn clock_unix_timestamp() -> RR<u64> {
+    rr(ClockField(clock_field::UNIX_TIMESTAMP))
+}
+pub fn rent_minimum_balance(data_len: impl ToRR<u64>) -> RR<u64> {
+    rr(RentMinimumBalance(data_len.rr()))
+}
+
+/*
+ * Resources provided by the host in EvaluatorContext.resources
+ */
+pub fn resource<T: SchemaType>(name: impl ToRR<String>) -> RR<Option<T>> {
+    rr(ResourceGet(name.rr(), PhantomData))
+}
+pub fn set_resource<T: SchemaType>(name: impl ToRR<String>, value: impl ToRR<T>) -> RR<()> {
+    rr(ResourceSet(name.rr(), value.rr()))
+}
+
+pub trait HostFnCall<I: HostArgs, O: SchemaType> {
+    fn call(&self, args: I::Args) -> RR<O>;
+}
+impl<I: HostArgs, O: SchemaType> HostFnCall<I, O> for HostFn<I, O> {
+    fn call(&self, args: I::Args) -> RR<O> {
+        rr(HostCall(self.0, self.signature(), I::erase(args), PhantomData))
+    }
+}
+
 pub fn m_if<O: SchemaType>(c: RR<bool>, r: impl ToRR<O>) -> RR<Option<O>> {
     rr(If(c, rr(ToSome(r.rr())).into(), Val::new(RD::none()).rr().into()))
 }
@@ -160,6 +517,10 @@ mod tests {
 
     use super::*;
     use crate::{compile::*, prelude::IntoProgram};
+    use quickcheck_macros::quickcheck;
+    use metalock_core::vm::eval::{Evaluator, EvaluatorContext, EvalError};
+    use solana_program::{account_info::AccountInfo, instruction::AccountMeta};
+    use solana_program::sysvar::{clock::Clock, rent::Rent};
 
     #[test]
     fn test_and() {
@@ -214,6 +575,175 @@ mod tests {
         assert!(comp.eval() == vec![8u8, 9].into());
     }
 
+    #[test]
+    fn test_list_combinators() {
+        let v = || Val::from(vec![3u8, 1, 4, 1, 5]).rr();
+        assert_eq!(v().filter(|i| i.greater_than(2)).eval(), vec![3u8, 4, 5].into());
+        assert_eq!(v().fold(100u8, |acc, i| acc.add(i)).eval(), 114u8.into());
+        assert_eq!(v().reduce(|acc, i| acc.add(i)).eval(), Some(14u8).into());
+        assert_eq!(Val::from(Vec::<u8>::new()).reduce(|acc, i| acc.add(i)).eval(), None::<u8>.into());
+        assert_eq!(v().find(|i| i.greater_than(3)).eval(), Some(4u8).into());
+        assert_eq!(v().find(|i| i.greater_than(5)).eval(), None::<u8>.into());
+        assert_eq!(v().position(|i| i.equals(1)).eval(), Some(1u16).into());
+        assert_eq!(v().position(|i| i.equals(9)).eval(), None::<u16>.into());
+        assert_eq!(v().sum().eval(), 14u8.into());
+        assert_eq!(Val::from(Vec::<u64>::new()).sum().eval(), 0u64.into());
+        assert_eq!(v().count(|i| i.equals(1)).eval(), 2u16.into());
+        assert_eq!(v().reverse().eval(), vec![5u8, 1, 4, 1, 3].into());
+        assert_eq!(v().take(2).eval(), vec![3u8, 1].into());
+        assert_eq!(v().take(10).eval(), vec![3u8, 1, 4, 1, 5].into());
+        assert_eq!(v().push(9).eval(), vec![3u8, 1, 4, 1, 5, 9].into());
+        assert_eq!(v().concat(vec![2u8, 6]).eval(), vec![3u8, 1, 4, 1, 5, 2, 6].into());
+        assert_eq!(range(3).eval(), vec![0u16, 1, 2].into());
+        assert_eq!(
+            v().zip(range(3)).eval(),
+            vec![(3u8, 0u16), (1, 1), (4, 2)].into()
+        );
+        assert_eq!(
+            v().take(2).enumerate().eval(),
+            vec![(0u16, 3u8), (1, 1)].into()
+        );
+        // nested
+        assert_eq!(
+            range(4).map(|n| range(n).sum()).filter(|n| n.greater_than(0)).eval(),
+            vec![1u16, 3].into()
+        );
+    }
+
+    #[test]
+    fn test_sort() {
+        let v = || Val::from(vec![3u8, 1, 4, 1, 5]).rr();
+        assert_eq!(v().sort().eval(), vec![1u8, 1, 3, 4, 5].into());
+        assert_eq!(v().is_sorted().eval(), false.into());
+        assert_eq!(v().sort().is_sorted().eval(), true.into());
+        assert_eq!(v().dedup().eval(), vec![3u8, 1, 4, 1, 5].into());
+        assert_eq!(v().sort().dedup().eval(), vec![1u8, 3, 4, 5].into());
+        assert_eq!(Val::from(Vec::<u8>::new()).is_sorted().eval(), true.into());
+
+        // stable, by descending score
+        let scores = vec![("a".to_string(), 10u32), ("b".to_string(), 30), ("c".to_string(), 10)];
+        let mut r = Val::from(scores).rr()
+            .sort_by_key(|t| t.unpack().1.less_than(20u32))
+            .map(|t| t.unpack().0);
+        assert_eq!(r.eval(), vec!["b".to_string(), "a".to_string(), "c".to_string()].into());
+
+        let strs = vec!["bb".to_string(), "a".to_string(), "ccc".to_string()];
+        assert_eq!(
+            Val::from(strs).rr().sort_by_key(|s| s.length()).eval(),
+            vec!["a".to_string(), "bb".to_string(), "ccc".to_string()].into()
+        );
+    }
+
+    #[test]
+    fn test_sort_budget() {
+        let ctx = EvaluatorContext { cost_limit: Some(20000), ..Default::default() };
+        assert_eq!(range(1000).sort().length().eval_with_context(ctx, usize::MAX), 1000u16.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "BUDGET: cost limit exceeded")]
+    fn test_sort_budget_exceeded() {
+        let ctx = EvaluatorContext { cost_limit: Some(5000), ..Default::default() };
+        range(1000).sort().length().eval_with_context(ctx, usize::MAX);
+    }
+
+    #[test]
+    fn test_repeat() {
+        assert_eq!(repeat(5, 0u16, |i, acc| acc.add(i)).eval(), 10u16.into());
+        assert_eq!(repeat(0, 7u16, |i, acc| acc.add(i)).eval(), 7u16.into());
+        // powers of 2
+        let mut r = repeat(4, vec![1u32], |_, acc: RR<Vec<u32>>| {
+            let last = acc.clone().reverse().get(0);
+            acc.push(last.clone().add(last))
+        });
+        assert_eq!(r.eval(), vec![1u32, 2, 4, 8, 16].into());
+    }
+
+    #[test]
+    fn test_while() {
+        // stops as soon as the condition fails, well before the bound
+        let mut r = while_(100, 1u32, |n| n.less_than(100), |n| n.clone().add(n));
+        assert_eq!(r.eval(), 128u32.into());
+        let mut r = while_(100, 500u32, |n| n.less_than(100), |n| n.clone().add(n));
+        assert_eq!(r.eval(), 500u32.into());
+        // exactly at the bound
+        let mut r = while_(7, 0u8, |n| n.less_than(7), |n| n.add(1));
+        assert_eq!(r.eval(), 7u8.into());
+        // drop leading zeros
+        let mut r = while_(10, vec![0u8, 0, 3, 0], |v| v.get(0).equals(0), |v| v.slice(1));
+        assert_eq!(r.eval(), vec![3u8, 0].into());
+    }
+
+    #[test]
+    #[should_panic(expected = "WHILE: exceeded 7 iterations")]
+    fn test_while_max_iterations() {
+        while_(7, 0u8, |n| n.less_than(10), |n| n.add(1)).eval();
+    }
+
+    #[test]
+    #[should_panic(expected = "BUDGET: cost limit exceeded")]
+    fn test_repeat_budget() {
+        let ctx = EvaluatorContext { cost_limit: Some(1000), ..Default::default() };
+        repeat(10000, 0u8, |_, acc| acc).eval_with_context(ctx, usize::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "SUM: overflow")]
+    fn test_sum_overflow() {
+        Val::from(vec![200u8, 100]).sum().eval();
+    }
+
+    #[test]
+    fn test_list_combinators_native() {
+        let meta = |n: u8, is_signer: bool| AccountMeta {
+            pubkey: Pubkey::new_from_array([n; 32]), is_signer, is_writable: false
+        };
+        let call = MetalockProxyCall {
+            program_id: Pubkey::default(),
+            data: Buffer(vec![]),
+            accounts: vec![meta(1, true), meta(2, false), meta(3, true)]
+        };
+        fn run<O: SchemaType>(call: &MetalockProxyCall, f: fn(RR<Vec<AccountMeta>>) -> RR<O>) -> RD {
+            (move |c: RR<MetalockProxyCall>| f(c.get_accounts())).to_program().run(call.clone(), Default::default())
+        }
+        assert_eq!(run(&call, |a| a.count(|a| a.get_is_signer())), 2u16.into());
+        assert_eq!(run(&call, |a| a.position(|a| a.get_is_signer().not())), Some(1u16).into());
+        assert_eq!(
+            run(&call, |a| a.filter(|a| a.get_is_signer()).map(|a| a.get_pubkey())),
+            vec![Pubkey::new_from_array([1; 32]), Pubkey::new_from_array([3; 32])].into()
+        );
+        assert_eq!(
+            run(&call, |a| a.reverse().enumerate().map(|t| t.unpack().0)),
+            vec![0u16, 1, 2].into()
+        );
+        assert_eq!(
+            run(&call, |a| a.find(|a| a.get_pubkey().equals(Pubkey::new_from_array([2; 32]))).map(|a| a.get_is_signer())),
+            Some(false).into()
+        );
+    }
+
+    #[test]
+    fn test_count_overflow() {
+        let call = MetalockProxyCall { program_id: Pubkey::default(), data: Buffer(vec![]), accounts: vec![AccountMeta::default(); 65536] };
+        let prog = |c: RR<MetalockProxyCall>| c.get_accounts().count(|a| a.get_is_writable().not());
+        let r = prog.to_program().try_run(call, Default::default());
+        assert_eq!(r, Err(EvalError::Failed("COUNT: overflow".into())));
+    }
+
+    #[test]
+    fn test_sort_native() {
+        let meta = |n: u8| AccountMeta::new_readonly(Pubkey::new_from_array([n; 32]), false);
+        let call = MetalockProxyCall { program_id: Pubkey::default(), data: Buffer(vec![]), accounts: vec![meta(2), meta(1)] };
+
+        // Natives have no order, so the DSL can't sort them but crafted code can
+        let prog = |c: RR<MetalockProxyCall>| {
+            let accounts = unsafe { std::mem::transmute::<RR<Vec<AccountMeta>>, RR<Vec<u8>>>(c.get_accounts()) };
+            rr(Sort(accounts)).length()
+        };
+        let r = prog.to_program().try_run(call, Default::default());
+        assert!(matches!(r, Err(EvalError::Failed(e)) if str::starts_with(&e, "SORT: not comparable")));
+    }
+
     #[test]
     fn test_any() {
         let v = vec![7u8, 0xcc, 9];
@@ -279,6 +809,163 @@ mod tests {
         assert!(r == true.into());
     }
 
+    #[test]
+    fn test_let() {
+        use std::{cell::Cell, rc::Rc};
+        const NEXT: HostFn<(), u8> = HostFn::new(1);
+        let calls = Rc::new(Cell::new(0u8));
+        let mut ctx = EvaluatorContext::default();
+        let c = calls.clone();
+        ctx.host_functions.register(&NEXT, move |_| { c.set(c.get() + 1); Ok(c.get().into()) });
+
+        let mut r = NEXT.call(()).bind_var(|v| v.clone().add(v.clone()).add(v));
+        assert_eq!(r.eval_with_context(ctx.clone(), usize::MAX), 3u8.into());
+        assert_eq!(calls.get(), 1);
+
+        let mut r = let_(10u8, |a| let_(a.clone().add(1), |b| b.add(a)));
+        assert_eq!(r.eval(), 21u8.into());
+    }
+
+    #[test]
+    fn test_let_slots() {
+        let slots = |mut r: RR<u8>| {
+            let mut ctx = EncodeContext::new();
+            r.op_encode(&mut ctx);
+            ctx.var_slots()
+        };
+        // siblings share a slot
+        assert_eq!(slots(let_(1u8, |a| a.add(1)).add(let_(2u8, |b| b.add(1)))), 1);
+        // nested need their own
+        assert_eq!(slots(let_(1u8, |a| let_(2u8, |b| b.add(a)))), 2);
+
+        // the program input keeps slot 0
+        let prog = |n: RR<u8>| let_(5u8, |x| x.add(n.clone())).add(let_(1u8, |y| y.add(n)));
+        assert_eq!(prog.to_program().run(1, Default::default()), 8u8.into());
+    }
+
+    #[test]
+    fn test_sub_mul() {
+        assert_eq!(10u8.rr().sub(3).eval(), 7u8.into());
+        assert_eq!(10u64.rr().mul(3u64).eval(), 30u64.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "SUB: overflow")]
+    fn test_sub_overflow() {
+        3u8.rr().sub(4).eval();
+    }
+
+    fn factorial() -> RR<Function<u64, u64>> {
+        fix(|fact, n: RR<u64>| {
+            rr(If(
+                n.clone().equals(0u64),
+                1u64.rr(),
+                // recurse first, so n is read after the inner call returns
+                fact.call(n.clone().sub(1u64)).mul(n)
+            ))
+        })
+    }
+
+    // Deeper than DEFAULT_MAX_CALL_DEPTH, which is sized for SBF
+    fn deep() -> EvaluatorContext {
+        EvaluatorContext { max_call_depth: Some(32), ..Default::default() }
+    }
+
+    #[test]
+    fn test_recursion_factorial() {
+        assert_eq!(factorial().call(0u64).eval(), 1u64.into());
+        assert_eq!(factorial().call(5u64).eval(), 120u64.into());
+        assert_eq!(factorial().call(20u64).eval_with_context(deep(), usize::MAX), 2432902008176640000u64.into());
+        // as a program
+        let prog = |n: RR<u64>| factorial().call(n.clone()).add(n);
+        assert_eq!(prog.to_program().run(10, deep()), 3628810u64.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "CALL: max call depth exceeded: 5")]
+    fn test_recursion_max_depth() {
+        let ctx = EvaluatorContext { max_call_depth: Some(5), ..Default::default() };
+        factorial().call(10u64).eval_with_context(ctx, usize::MAX);
+    }
+
+    #[test]
+    fn test_recursion_tree_walk() {
+        //        0:1
+        //      /     \
+        //    1:2     2:3
+        //   /   \      \
+        // 3:4   4:5    5:6
+        let nodes: Vec<(u32, Vec<u16>)> = vec![
+            (1, vec![1, 2]), (2, vec![3, 4]), (3, vec![5]), (4, vec![]), (5, vec![]), (6, vec![])
+        ];
+        let tree = || Val::from(nodes.clone()).rr();
+
+        let t = tree();
+        let sum = fix(move |sum, idx: RR<u16>| {
+            let (value, children) = t.clone().get(idx).unpack();
+            children.map(move |c| sum.call(c)).sum().add(value)
+        });
+        assert_eq!(sum.call(0).eval(), 21u32.into());
+        assert_eq!(sum.call(1).eval(), 11u32.into());
+
+        // depth of the tree, with a let in the body
+        let t = tree();
+        let depth = fix(move |depth, idx: RR<u16>| {
+            let_(t.clone().get(idx).unpack().1, move |children| {
+                children
+                    .map(move |c| depth.call(c))
+                    .fold(0u8, |acc, d| rr(If(d.clone().greater_than(acc.clone()), d, acc)))
+                    .add(1u8)
+            })
+        });
+        assert_eq!(depth.call(0).eval(), 3u8.into());
+    }
+
+    #[quickcheck]
+    fn prop_nested_any(xs: Vec<u8>, ys: Vec<u8>) -> bool {
+        let expected = xs.iter().map(|x| ys.contains(x)).collect::<Vec<_>>();
+        let ys_rr = Val::from(ys).rr();
+        let r = Val::from(xs).rr().map(move |x| ys_rr.any(move |y| y.equals(x.clone()))).eval();
+        r == expected.into()
+    }
+
+    #[quickcheck]
+    fn prop_nested_filter_count(xs: Vec<u8>, ys: Vec<u8>) -> bool {
+        let expected = xs.iter().cloned().filter(|x| ys.iter().filter(|y| *y < x).count() > 1).collect::<Vec<_>>();
+        let ys_rr = Val::from(ys).rr();
+        let r = Val::from(xs).rr()
+            .filter(move |x| ys_rr.clone().count(move |y| y.less_than(x.clone())).greater_than(1))
+            .eval();
+        r == expected.into()
+    }
+
+    #[quickcheck]
+    fn prop_let_in_nested_map(xs: Vec<u8>, ys: Vec<u8>) -> bool {
+        let (xs, ys): (Vec<u16>, Vec<u16>) = (xs.into_iter().map(Into::into).collect(), ys.into_iter().map(Into::into).collect());
+        let expected = xs.iter().map(|x| ys.iter().map(|y| 2 * x + y).collect::<Vec<_>>()).collect::<Vec<_>>();
+        let ys_rr = Val::from(ys).rr();
+        let mut r = Val::from(xs).rr().map(move |x| {
+            let ys_rr = ys_rr.clone();
+            let_(x.clone().add(x), move |x2| ys_rr.map(move |y| let_(y, |y| x2.clone().add(y))))
+        });
+        // compiles the same each time
+        let code = r.encode();
+        code == r.encode() && r.eval() == expected.into()
+    }
+
+    #[quickcheck]
+    fn prop_recursion_in_map(xs: Vec<u8>) -> bool {
+        let xs: Vec<u64> = xs.into_iter().take(10).map(|x| (x % 15) as u64).collect();
+        let fact = |n: u64| (1..=n).product::<u64>();
+        let expected = xs.iter().map(|x| xs.iter().filter(|y| fact(**y) > fact(*x)).count() as u16).collect::<Vec<_>>();
+        let xs_rr = Val::from(xs.clone()).rr();
+        let r = Val::from(xs).rr().map(move |x| {
+            let fx = factorial().call(x);
+            xs_rr.clone().count(move |y| factorial().call(y).greater_than(fx.clone()))
+        }).eval_with_context(deep(), usize::MAX);
+        r == expected.into()
+    }
+
     #[test]
     fn test_function() {
         // functions take a reference to an offset and their variables are stored as I+offset
@@ -288,6 +975,29 @@ mod tests {
         assert_eq!(r.eval(), 11u8.into());
     }
 
+    #[test]
+    #[should_panic(expected = "and may outlive it")]
+    fn test_function_escapes() {
+        // The returned function would read n after the call has restored it
+        let adder = to_function(|n: RR<u8>| to_function(move |x: RR<u8>| x.add(n.clone())));
+        adder.call(1u8).call(2u8).eval();
+    }
+
+    #[test]
+    #[should_panic(expected = "and may outlive it")]
+    fn test_function_escapes_let() {
+        let_(1u8, |n| to_function(move |x: RR<u8>| x.add(n.clone()))).call(2u8).eval();
+    }
+
+    #[test]
+    fn test_function_captures_in_scope() {
+        // Functions that refer to a binding can be used within its scope
+        let mut r = let_(1u8, |n| to_function(move |x: RR<u8>| x.add(n.clone())).call(2u8));
+        assert_eq!(r.eval(), 3u8.into());
+        let mut r = let_(1u8, |n| Val::from(vec![1u8, 2]).rr().map(move |x| x.add(n.clone())));
+        assert_eq!(r.eval(), vec![2u8, 3].into());
+    }
+
     #[test]
     #[should_panic]
     fn test_overflow() {
@@ -307,6 +1017,88 @@ mod tests {
         println!("r is: {:?}", result);
     }
 
+    #[test]
+    fn test_try() {
+        let v = || Val::from(vec![1u8, 2, 3]);
+        assert_eq!(try_(v().get(1)).eval(), Some(2u8).into());
+        assert_eq!(try_(v().get(5)).eval(), None::<u8>.into());
+        assert_eq!(try_(assert(false, "nope").then(1u8)).eval(), None::<u8>.into());
+        assert_eq!(try_(200u8.rr().add(100)).eval(), None::<u8>.into());
+        assert_eq!(try_(v().get(5)).m_else(0).eval(), 0u8.into());
+
+        let mut r = Val::from(vec![0u16, 5, 2]).map(move |i| try_(v().get(i)));
+        assert_eq!(r.eval(), vec![Some(1u8), None, Some(3)].into());
+    }
+
+    #[test]
+    fn test_assert_code() {
+        assert_eq!(assert_code(true, 1).then(1u8).eval(), 1u8.into());
+        assert_eq!(try_(assert_code(false, 1).then(1u8)).eval(), None::<u8>.into());
+        // 4 bytes of code instead of the message
+        let a = assert(false, "amount exceeds the daily limit").op_encode(&mut EncodeContext::new()).join();
+        let b = assert_code(false, 6001).op_encode(&mut EncodeContext::new()).join();
+        assert!(b.len() < a.len() / 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "ASSERT_CODE: 6001")]
+    fn test_assert_code_fails() {
+        assert_code(false, 6001).eval();
+    }
+
+    #[test]
+    fn test_try_restores_state() {
+        // var writes in the failed body are rolled back
+        let var = Var::<u8>::new();
+        let failed = try_(rr(SetVar(var.0, 2u8.rr())).then(panic("fail")));
+        let mut r = rr(SetVar(var.0, 1u8.rr())).then(let_(failed, move |_| rr(var)));
+        assert_eq!(r.eval(), 1u8.into());
+
+        // frames of the failed calls are dropped, factorial(5) needs 6 of 8
+        let deep = fix(|f: RR<Function<u8, u8>>, n: RR<u8>| assert(n.clone().less_than(3), "too deep").then(f.call(n.add(1))));
+        let ctx = EvaluatorContext { max_call_depth: Some(8), ..Default::default() };
+        let mut r = let_(try_(deep.call(0u8)), |_| factorial().call(5u64));
+        assert_eq!(r.eval_with_context(ctx, usize::MAX), 120u64.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "BUDGET: cost limit exceeded")]
+    fn test_try_limits() {
+        let ctx = EvaluatorContext { cost_limit: Some(1000), ..Default::default() };
+        try_(repeat(10000, 0u8, |_, acc| acc)).eval_with_context(ctx, usize::MAX);
+    }
+
+    #[test]
+    fn test_try_run() {
+        let p = (|v: RR<Vec<u8>>| v.get(3)).to_program();
+        assert_eq!(p.try_run(vec![1u8], Default::default()), Err(EvalError::Failed("INDEX: index 3 out of range (1)".into())));
+        assert_eq!(p.try_run(vec![1u8, 2, 3, 4], Default::default()), Ok(4u8.into()));
+    }
+
+    #[test]
+    fn test_invalid_opcode() {
+        for byte in 0..=255u8 {
+            if let Ok(op) = OP::try_from(byte) {
+                assert_eq!(Into::<u8>::into(op), byte);
+            }
+        }
+        assert_eq!(OP::try_from(0x01), Err(0x01));
+
+        let run = |code: Vec<u8>| Evaluator::new(&mut code.as_ref(), Default::default()).try_run(RD::Unit());
+        let mut code = 1u8.rr().equals(2u8).op_encode(&mut EncodeContext::new()).join();
+        assert_eq!(run(code.clone()), Ok(false.into()));
+        let n = code.len();
+        code[n-5] = 0x08;
+        assert_eq!(run(code), Err(EvalError::InvalidOpcode(0x08, n-5)));
+        assert_eq!(run(vec![0]), Err(EvalError::InvalidOpcode(0, 0)));
+
+        // offsets in a function body are relative to the body
+        let f = EncodedFunction(1, vec![OP::NOT(Default::default()).into(), 0x01]);
+        let f: RR<Function<u8, bool>> = rr(Val::new(f.into()));
+        let code = f.call(1u8).op_encode(&mut EncodeContext::new()).join();
+        assert_eq!(run(code), Err(EvalError::InvalidOpcode(0x01, 1)));
+    }
+
     #[test]
     fn test_catp() {
         fn p(n: RR<u32>) -> RR<bool> {
@@ -316,5 +1108,346 @@ mod tests {
         let b = program.compile();
         println!("{:02X?}", b);
     }
-}
 
+    #[test]
+    fn test_compare() {
+        assert_eq!(3u64.rr().less_than(4).eval(), true.into());
+        assert_eq!(4u64.rr().less_than(4).eval(), false.into());
+        assert_eq!(4u64.rr().less_than_eq(4).eval(), true.into());
+        assert_eq!(5u64.rr().greater_than(4).eval(), true.into());
+        assert_eq!(3u64.rr().greater_than_eq(4).eval(), false.into());
+        assert_eq!("abc".to_string().rr().less_than("abd".to_string()).eval(), true.into());
+        assert_eq!(vec![1u8, 2].rr().less_than(vec![1u8, 2, 0]).eval(), true.into());
+    }
+
+    #[test]
+    fn test_hash() {
+        use solana_program::{hash, keccak, blake3};
+        let b = Buffer(b"metalock".to_vec());
+        assert_eq!(b.rr().sha256().eval(), hash::hash(b"metalock").to_bytes().into());
+        assert_eq!(b.rr().keccak256().eval(), keccak::hash(b"metalock").to_bytes().into());
+        assert_eq!("metalock".to_string().rr().blake3().eval(), blake3::hash(b"metalock").to_bytes().into());
+        assert_eq!([1u8; 32].rr().sha256().eval(), hash::hash(&[1; 32]).to_bytes().into());
+
+        let preimage = [2u8; 32].rr().concat_bytes(Buffer(b"salt".to_vec()));
+        assert_eq!(preimage.sha256().eval(), hash::hashv(&[&[2; 32], b"salt"]).to_bytes().into());
+    }
+
+    #[test]
+    fn test_string_ops() {
+        let s = || "hello world".to_string().rr();
+        assert_eq!(s().concat_str("!".to_string()).eval(), "hello world!".into());
+        assert_eq!(s().slice_bytes(6, 11).eval(), "world".into());
+        assert_eq!(s().starts_with("hello".to_string()).eval(), true.into());
+        assert_eq!(s().starts_with("world".to_string()).eval(), false.into());
+        assert_eq!(s().ends_with("world".to_string()).eval(), true.into());
+        assert_eq!(s().contains_bytes("o w".to_string()).eval(), true.into());
+        assert_eq!(s().contains_bytes("ow".to_string()).eval(), false.into());
+        assert_eq!(s().byte_at(4).eval(), b'o'.into());
+        assert_eq!(s().to_buffer().eval(), Buffer(b"hello world".to_vec()).into());
+        assert_eq!(s().to_buffer().utf8().eval(), Some("hello world".to_string()).into());
+        assert_eq!(Buffer(vec![0xff, 0xfe]).rr().utf8().eval(), None::<String>.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "not on char boundary")]
+    fn test_string_slice_char_boundary() {
+        "héllo".to_string().rr().slice_bytes(0, 2).eval();
+    }
+
+    #[test]
+    fn test_buffer_le() {
+        let mut data = vec![7u8];
+        data.extend(1000u16.to_le_bytes());
+        data.extend(123456789u64.to_le_bytes());
+        let b = || Buffer(data.clone()).rr();
+
+        assert_eq!(b().read_le::<u8>(0).eval(), 7u8.into());
+        assert_eq!(b().read_le::<u16>(1).eval(), 1000u16.into());
+        assert_eq!(b().read_le::<u64>(3).eval(), 123456789u64.into());
+        assert_eq!(b().slice_bytes(1, 3).eval(), Buffer(vec![0xe8, 0x03]).into());
+        assert_eq!(1000u16.rr().to_le_bytes().eval(), Buffer(vec![0xe8, 0x03]).into());
+        assert_eq!(
+            123456789u64.rr().to_le_bytes().read_le::<u64>(0u16).eval(),
+            123456789u64.into()
+        );
+
+        // The size comes from the code, and may be invalid
+        let prog = |b: RR<Buffer>| rr(FromLeBytes::<Buffer, u64>(b, 0u16.rr(), 3, PhantomData));
+        let r = prog.to_program().try_run(Buffer(data.clone()), Default::default());
+        assert_eq!(r, Err(EvalError::Failed("FROM_LE_BYTES: invalid size: 3".into())));
+    }
+
+    #[test]
+    #[should_panic(expected = "FROM_LE_BYTES: 8 bytes at offset 4 out of bounds (11)")]
+    fn test_buffer_le_out_of_bounds() {
+        let data = vec![0u8; 11];
+        Buffer(data).rr().read_le::<u64>(4).eval();
+    }
+
+    #[test]
+    fn test_ed25519_verify() {
+        use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+        let secret = SecretKey::from_bytes(&[1; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        let keypair = Keypair { secret, public };
+        let pubkey = Pubkey::new_from_array(keypair.public.to_bytes());
+        let message = Buffer(b"user X may withdraw 10".to_vec());
+        let signature = Buffer(keypair.sign(&message).to_bytes().to_vec());
+
+        assert_eq!(ed25519_verify(pubkey, message.clone(), signature.clone()).eval(), true.into());
+        assert_eq!(ed25519_verify(pubkey, Buffer(b"user X may withdraw 11".to_vec()), signature.clone()).eval(), false.into());
+        assert_eq!(ed25519_verify(Pubkey::new_from_array([2; 32]), message.clone(), signature).eval(), false.into());
+        assert_eq!(ed25519_verify(pubkey, message, Buffer(vec![0; 10])).eval(), false.into());
+    }
+
+    #[test]
+    fn test_secp256k1_recover() {
+        let secret = libsecp256k1::SecretKey::parse(&[1; 32]).unwrap();
+        let public = libsecp256k1::PublicKey::from_secret_key(&secret).serialize();
+        let hash = solana_program::keccak::hash(b"permit").to_bytes();
+        let (sig, recovery_id) = libsecp256k1::sign(&libsecp256k1::Message::parse(&hash), &secret);
+        let sig = Buffer(sig.serialize().to_vec());
+
+        let mut r = secp256k1_recover(hash, recovery_id.serialize(), sig.clone());
+        assert_eq!(r.eval(), Some(Buffer(public[1..].to_vec())).into());
+        let mut r = secp256k1_recover(hash, 5, sig);
+        assert_eq!(r.eval(), None::<Buffer>.into());
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        use solana_program::keccak::hashv;
+        let node = |a: [u8; 32], b: [u8; 32]| hashv(&[&a.min(b), &a.max(b)]).to_bytes();
+        let leaves: Vec<[u8; 32]> = (0u8..4).map(|i| hashv(&[&[i]]).to_bytes()).collect();
+        let root = node(node(leaves[0], leaves[1]), node(leaves[2], leaves[3]));
+        let proof = vec![leaves[3], node(leaves[0], leaves[1])];
+
+        assert_eq!(verify_merkle_proof(leaves[2], proof.clone(), root).eval(), true.into());
+        assert_eq!(verify_merkle_proof(leaves[1], proof.clone(), root).eval(), false.into());
+        assert_eq!(verify_merkle_proof(root, vec![], root).eval(), true.into());
+    }
+
+    #[test]
+    fn test_program_address() {
+        let program_id = Pubkey::new_from_array([7; 32]);
+        let user = Pubkey::new_from_array([8; 32]);
+        let seeds = || vec![Buffer(b"vault".to_vec()), Buffer(user.to_bytes().to_vec())];
+
+        let (pda, bump) = Pubkey::find_program_address(&[b"vault", user.as_ref()], &program_id);
+        let (mut rr_pda, mut rr_bump) = find_program_address(seeds(), program_id).unpack();
+        assert_eq!(rr_pda.eval(), pda.into());
+        assert_eq!(rr_bump.eval(), bump.into());
+
+        let with_bump = [seeds(), vec![Buffer(vec![bump])]].concat();
+        assert_eq!(create_program_address(with_bump, program_id).eval(), Some(pda).into());
+
+        // find the first bump that lands on the curve
+        let on_curve = (0..=255u8).find(|b| Pubkey::create_program_address(&[b"vault", user.as_ref(), &[*b]], &program_id).is_err()).unwrap();
+        let seeds = [seeds(), vec![Buffer(vec![on_curve])]].concat();
+        assert_eq!(create_program_address(seeds, program_id).eval(), None::<Pubkey>.into());
+    }
+
+    #[test]
+    fn test_host_call() {
+        const ADD_PRICE: HostFn<(u8, u32), u64> = HostFn::new(1);
+        const TIER: HostFn<(), u8> = HostFn::new(2);
+
+        let mut ctx = EvaluatorContext::default();
+        ctx.host_functions
+            .register(&ADD_PRICE, |args| Ok((args[0]._as::<u8>() as u64 + args[1]._as::<u32>() as u64).into()))
+            .register(&TIER, |_| Ok(3u8.into()));
+
+        let mut comp = ADD_PRICE.call((TIER.call(()), 10u32.rr()));
+        assert_eq!(comp.eval_with_context(ctx, usize::MAX), 13u64.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "HOSTCALL: unknown function: 5")]
+    fn test_host_call_unknown() {
+        const MISSING: HostFn<(), u8> = HostFn::new(5);
+        MISSING.call(()).eval();
+    }
+
+    #[test]
+    #[should_panic(expected = "HOSTCALL 1: oracle offline")]
+    fn test_host_call_error() {
+        const PRICE: HostFn<(), u64> = HostFn::new(1);
+        let mut ctx = EvaluatorContext::default();
+        ctx.host_functions.register(&PRICE, |_| Err("oracle offline".to_string()));
+        PRICE.call(()).eval_with_context(ctx, usize::MAX);
+    }
+
+    #[test]
+    fn test_host_call_signature() {
+        const PRICE: HostFn<(u8,), u64> = HostFn::new(1);
+        const OTHER: HostFn<(u8,), u8> = HostFn::new(1);
+        let mut prog = (|n: RR<u8>| PRICE.call((n,))).to_program();
+        let code = prog.compile();
+
+        // Refused on load if the host has another signature
+        let mut ctx = EvaluatorContext::default();
+        ctx.host_functions.register(&OTHER, |args| Ok(args[0].clone()));
+        let expected = "HOSTCALL 1: expected Function<(u8), u8>, program has Function<(u8), u64>";
+        assert_eq!(Evaluator::load(&mut code.as_ref(), ctx.clone()).err(), Some(EvalError::Unsupported(expected.into())));
+        assert_eq!(prog.try_run(1, ctx), Err(EvalError::Failed(expected.into())));
+
+        // Args and results are checked
+        let mut ctx = EvaluatorContext::default();
+        ctx.host_functions.register(&PRICE, |args| Ok(args[0].clone()));
+        let r = Evaluator::load(&mut code.as_ref(), ctx.clone()).unwrap().try_run(1u8.into());
+        assert_eq!(r, Err(EvalError::Failed("HOSTCALL 1: result is not a u64".into())));
+        let bad_arg = |_: RR<()>| rr(HostCall::<u64>(1, PRICE.signature(), <(u16,)>::erase((5u16.rr(),)), PhantomData));
+        let r = bad_arg.to_program().try_run((), ctx);
+        assert_eq!(r, Err(EvalError::Failed("HOSTCALL 1: arg 0 is not a u8".into())));
+    }
+
+    fn leak<T>(t: T) -> &'static mut T {
+        Box::leak(Box::new(t))
+    }
+
+    fn account_context() -> EvaluatorContext {
+        let meta = AccountMeta::new_readonly(Pubkey::new_from_array([3; 32]), true);
+        let data = [&[0xff; 8][..], unsafe { &*(&meta as *const AccountMeta as *const [u8; 34]) }].concat();
+        let accounts = vec![
+            AccountInfo::new(leak(Pubkey::new_from_array([1; 32])), true, false, leak(100), leak(data).as_mut_slice(), leak(Pubkey::new_from_array([2; 32])), false, 0),
+            AccountInfo::new(leak(Pubkey::new_from_array([4; 32])), false, true, leak(5), leak(vec![]).as_mut_slice(), leak(Pubkey::default()), false, 0),
+        ];
+        EvaluatorContext { accounts: leak(accounts).as_slice(), ..Default::default() }
+    }
+
+    #[test]
+    fn test_account_fields() {
+        let eval = |mut r: RR<bool>| r.eval_with_context(account_context(), usize::MAX) == true.into();
+        assert!(eval(account_key(0).equals(Pubkey::new_from_array([1; 32]))));
+        assert!(eval(account_owner(0).equals(Pubkey::new_from_array([2; 32]))));
+        assert!(eval(account_lamports(1).equals(5u64)));
+        assert!(eval(account_is_signer(0).and(account_is_signer(1).not())));
+        assert!(eval(account_is_writable(1).and(account_is_writable(0).not())));
+        assert!(eval(account_data(0).length().equals(42)));
+        assert!(eval(account_data(1).equals(Buffer(vec![]))));
+
+        let prog = |_: RR<()>| rr(AccountField::<u64>(0u16.rr(), 9, PhantomData));
+        let r = prog.to_program().try_run((), account_context());
+        assert_eq!(r, Err(EvalError::Failed("ACCOUNT: invalid field: 9".into())));
+    }
+
+    #[test]
+    fn test_account_native() {
+        let mut key = account_data_as::<AccountMeta>(0, 8).get_pubkey();
+        assert_eq!(key.eval_with_context(account_context(), usize::MAX), Pubkey::new_from_array([3; 32]).into());
+        let mut is_signer = account_data_as::<AccountMeta>(0, 8).get_is_signer();
+        assert_eq!(is_signer.eval_with_context(account_context(), usize::MAX), true.into());
+
+        // Compiled, the native struct schema is in the code
+        let mut prog = (|idx: RR<u16>| account_data_as::<AccountMeta>(idx, 8).get_is_signer()).to_program();
+        let code = prog.compile();
+        let mut eval = Evaluator::load(&mut code.as_ref(), account_context()).unwrap();
+        assert_eq!(eval.run(0u16.into()), true.into());
+
+        // Structs with pointers can't be viewed in account data
+        assert_eq!(Native::pod_size(&AccountMeta::to_schema()), Some(34));
+        assert_eq!(Native::pod_size(&MetalockProxyCall::to_schema()), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ACCOUNT_NATIVE: account 0 data too short: 42 < 43")]
+    fn test_account_native_short() {
+        account_data_as::<AccountMeta>(0, 9).get_pubkey().eval_with_context(account_context(), usize::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "ACCOUNT: index 2 out of range (2 accounts)")]
+    fn test_account_out_of_range() {
+        account_lamports(2).eval_with_context(account_context(), usize::MAX);
+    }
+
+    #[test]
+    fn test_sysvars() {
+        let ctx = EvaluatorContext {
+            clock: Clock { slot: 100, epoch: 2, unix_timestamp: 1_700_000_000, ..Default::default() },
+            rent: Rent::default(),
+            ..Default::default()
+        };
+        let eval = |mut r: RR<u64>| r.eval_with_context(ctx.clone(), usize::MAX)._as::<u64>();
+        assert_eq!(eval(clock_slot()), 100);
+        assert_eq!(eval(clock_epoch()), 2);
+        assert_eq!(eval(clock_unix_timestamp()), 1_700_000_000);
+        assert_eq!(eval(rent_minimum_balance(100)), Rent::default().minimum_balance(100));
+
+        let ctx = EvaluatorContext {
+            clock: Clock { unix_timestamp: -1, ..Default::default() },
+            ..Default::default()
+        };
+        assert_eq!(clock_unix_timestamp().eval_with_context(ctx, usize::MAX), 0u64.into());
+
+        let r = (|_: RR<()>| rr(ClockField(9))).to_program().try_run((), Default::default());
+        assert_eq!(r, Err(EvalError::Failed("CLOCK: invalid field: 9".into())));
+    }
+
+    #[cfg(feature = "anchor")]
+    mod invoke {
+        use super::*;
+        use std::cell::RefCell;
+        use metalock_core::vm::invoke::Invoker;
+        use solana_program::{entrypoint::ProgramResult, instruction::Instruction};
+
+        #[derive(Default)]
+        struct MockInvoker(RefCell<Vec<(Instruction, Vec<Vec<u8>>)>>);
+        impl Invoker for MockInvoker {
+            fn invoke_signed(&self, ix: &Instruction, _accounts: &[AccountInfo<'static>], signer_seeds: &[&[&[u8]]]) -> ProgramResult {
+                let seeds = signer_seeds[0].iter().map(|s| s.to_vec()).collect();
+                self.0.borrow_mut().push((ix.clone(), seeds));
+                Ok(())
+            }
+            fn get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+                let calls = self.0.borrow();
+                let (ix, _) = calls.last()?;
+                Some((ix.program_id, ix.data.iter().rev().cloned().collect()))
+            }
+        }
+
+        fn context(invoker: &'static MockInvoker, allowlist: Option<Vec<Pubkey>>) -> EvaluatorContext {
+            EvaluatorContext {
+                signer_seeds: vec![b"metalock"],
+                invoke_allowlist: allowlist,
+                invoker: Some(invoker),
+                ..Default::default()
+            }
+        }
+
+        fn prog(accounts: RR<Vec<AccountMeta>>) -> RR<Buffer> {
+            let program_id = Pubkey::new_from_array([1; 32]);
+            proxy_call(program_id, Buffer(vec![1, 2, 3]), accounts).invoke_signed().then(get_invoke_return())
+        }
+
+        #[test]
+        fn test_invoke_signed() {
+            let invoker = Box::leak(Box::new(MockInvoker::default()));
+            let accounts = vec![AccountMeta::new(Pubkey::new_from_array([2; 32]), true)];
+            let program_id = Pubkey::new_from_array([1; 32]);
+
+            let r = prog.to_program().run(accounts.clone(), context(invoker, Some(vec![program_id])));
+            assert_eq!(r._as::<&Buffer>(), &Buffer(vec![3, 2, 1]));
+
+            let calls = invoker.0.borrow();
+            assert_eq!(calls.len(), 1);
+            assert_eq!(calls[0].0, Instruction::new_with_bytes(program_id, &[1, 2, 3], accounts.clone()));
+            assert_eq!(calls[0].1, vec![b"metalock".to_vec()]);
+
+            // Compiled, with a function over the accounts
+            let mut signers = (|accounts: RR<Vec<AccountMeta>>| accounts.map(|a| a.get_is_signer())).to_program();
+            let code = signers.compile();
+            let r = Evaluator::load(&mut code.as_ref(), Default::default()).unwrap().run(accounts.into());
+            assert_eq!(r, vec![true].into());
+        }
+
+        #[test]
+        #[should_panic(expected = "program not allowed")]
+        fn test_invoke_allowlist() {
+            let invoker = Box::leak(Box::new(MockInvoker::default()));
+            let allowlist = Some(vec![Pubkey::new_from_array([9; 32])]);
+            prog.to_program().run(vec![], context(invoker, allowlist));
+        }
+    }
+}
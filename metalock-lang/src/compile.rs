@@ -1,5 +1,4 @@
-use std::{collections::BTreeMap, fmt::Debug, hash::Hasher, usize};
-use std::collections::hash_map::DefaultHasher;
+use std::{collections::BTreeMap, fmt::Debug, usize};
 
 use metalock_core::internal::*;
 use metalock_core::vm::{eval::{Evaluator, EvaluatorContext}, expr::*};
@@ -11,12 +10,18 @@ pub trait OpEval<R: Debug>: Op<R> {
         self.eval_with_context(Default::default(), usize::MAX)
     }
     fn encode(&mut self) -> Vec<u8> {
-        self.op_encode(&mut EncodeContext::new()).join()
+        self.op_tree().join()
     }
     fn eval_with_context(&mut self, ctx: EvaluatorContext, dedupe_threshold: usize) -> RD {
-        let o = self.op_encode(&mut EncodeContext::new()).join_threshold(dedupe_threshold);
+        let o = self.op_tree().join_threshold(dedupe_threshold);
         Evaluator::new(&mut o.as_ref(), ctx).run(RD::Unit())
     }
+    fn op_tree(&mut self) -> OpTree {
+        let mut ctx = EncodeContext::new();
+        let tree = self.op_encode(&mut ctx);
+        ctx.check().unwrap_or_else(|e| panic!("encode: {}", e));
+        tree
+    }
 }
 
 
@@ -37,18 +42,18 @@ impl OpTreeImpl for OpTree {
     fn as_mut_op_tree(&mut self) -> &mut OpTree { self }
 }
 
+/*
+ * Repeated subtrees are keyed by their bytes rather than a hash, so that the
+ * output only depends on the input; program ids are hashes of it.
+ */
 struct OpTreeDedup {
     threshold: usize,
-    seen: BTreeMap<(usize, u64), (u16, u8)>
+    // Offset of the first occurrence, and the number of occurrences
+    seen: BTreeMap<(usize, Vec<u8>), (u16, usize)>
 }
 
 impl OpTreeDedup {
-    pub fn join(&mut self, off: u16, replace: Option<(u64, u16)>, op: &mut OpTree) -> Vec<u8> {
-        fn hash(v: &Vec<u8>) -> u64 {
-            let mut h = DefaultHasher::new();
-            h.write(v);
-            h.finish()
-        }
+    pub fn join(&mut self, off: u16, replace: Option<(&[u8], u16)>, op: &mut OpTree) -> Vec<u8> {
         match op {
             OpTree::Data(v) => v.clone(),
             OpTree::LengthPrefix(o) => {
@@ -60,19 +65,18 @@ impl OpTreeDedup {
                 // Create output vec
                 let mut out = opcode.as_slice().to_vec();
                 ops.iter_mut().for_each(|o| out.extend(self.join(off + out.len() as u16, replace, o)));
-                let h = hash(&out);
 
                 // Check if replace
                 if opcode.is_some() {
-                    if let Some((r_hash, r_off)) = replace {
-                        if r_hash == h && r_off < off {
+                    if let Some((r_code, r_off)) = replace {
+                        if r_code == out && r_off < off {
                             let mut v = vec![OP::FETCH().into()];
                             v.extend(r_off.rd_encode());
                             *op = OpTree::Data(v.clone());
                             return v;
                         }
                     } else if out.len() > self.threshold {
-                        self.seen.entry((out.len(), h)).and_modify(|r| r.1 += 1).or_insert((off, 1));
+                        self.seen.entry((out.len(), out.clone())).and_modify(|r| r.1 += 1).or_insert((off, 1));
                     }
                 }
 
@@ -90,8 +94,8 @@ impl OpTreeDedup {
             // remove non dupes
             self.seen = self.seen.clone().into_iter().filter(|e| e.1.1 > 1).collect();
 
-            if let Some(((_, h), (off, _))) = self.seen.last_key_value() {
-                self.join(0, Some((*h, *off)), tree);
+            if let Some(((_, code), (off, _))) = std::mem::take(&mut self.seen).pop_last() {
+                self.join(0, Some((&code, off)), tree);
             } else {
                 return out;
             }
@@ -113,4 +117,20 @@ mod tests {
         assert_eq!(comp.eval()._as::<u8>(), 112);
         assert_eq!(comp.eval_with_context(Default::default(), 5)._as::<u8>(), 112);
     }
+
+    #[test]
+    fn test_dedup_many() {
+        // A subtree repeated 256 times is deduplicated like any other
+        let big = "a long string".to_string();
+        let mut level = (0..256u16).map(|i| big.rr().equals(big.clone()).and(i.rr().equals(i))).collect::<Vec<_>>();
+        while level.len() > 1 {
+            level = level.chunks(2).map(|c| c[0].clone().and(c[1].clone())).collect();
+        }
+        let mut comp = level.pop().unwrap();
+        let code = comp.op_encode(&mut EncodeContext::new()).join_threshold(10);
+        let full = comp.op_encode(&mut EncodeContext::new()).join_threshold(usize::MAX);
+        // each copy but the first is a FETCH
+        assert!(code.len() < full.len() - 255 * 30);
+        assert_eq!(comp.eval_with_context(Default::default(), 10), true.into());
+    }
 }
@@ -1,10 +1,17 @@
 
 use std::marker::PhantomData;
 
+use solana_program::pubkey::Pubkey;
+
 use metalock_core::internal::*;
 use metalock_core::vm::eval::*;
+use metalock_core::vm::header::Header;
+use metalock_core::vm::resource::ResourceStore;
+use metalock_core::vm::verify::schema_len;
 use metalock_core::{anchor_derive, impl_into, impl_deref};
 
+use crate::program::program_hash;
+
 
 
 
@@ -18,174 +25,358 @@ anchor_derive!(
         Ptr(ResourceId)
     }
 
-    #[derive(Clone)]
+    #[derive(Clone, Default)]
     pub struct MetalockTest {
         hooks: MetalockHooks,
-        //resources: MetalockResources
+        resources: MetalockResources
     }
 
+    // Sorted so that hooks with the same schema and name are together, in
+    // order of priority, lowest first
     #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
     pub struct MetalockHook {
         schema: Schema,
         name: String,
-        code: Vec<u8>
+        priority: u8,
+        // program_hash of schema and code
+        id: [u8; 32],
+        code: Vec<u8>,
+        owner: Pubkey,
+        enabled: bool
     }
 
-    #[derive(Clone)]
-    pub struct MetalockHooks(Vec<MetalockHook>);
+    // Grouped by schema
+    #[derive(Clone, Default)]
+    pub struct MetalockResources(Vec<(Schema, Vec<(String, ResourceDataOrPtr)>)>);
+
+    #[derive(Clone, Default)]
+    pub struct MetalockHooks {
+        hooks: Vec<MetalockHook>,
+        // Max hooks per owner
+        owner_quota: Option<u16>
+    }
 );
 
-impl_deref!([], MetalockHooks => Vec<MetalockHook>, 0);
-pub type MetalockResources = Vec<(Schema, Vec<(String, ResourceDataOrPtr)>)>;
+impl_deref!([], MetalockHooks => Vec<MetalockHook>, hooks);
+
+impl MetalockHook {
+    pub fn id(&self) -> &[u8; 32] { &self.id }
+    pub fn name(&self) -> &str { &self.name }
+    pub fn schema(&self) -> &Schema { &self.schema }
+    pub fn owner(&self) -> &Pubkey { &self.owner }
+    pub fn priority(&self) -> u8 { self.priority }
+    pub fn enabled(&self) -> bool { self.enabled }
+    pub fn code(&self) -> &[u8] { &self.code }
+}
+
+// ie "inc: Function<u8, u8> id=1f2e3d4c priority=0"
+impl std::fmt::Display for MetalockHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} id=", self.name, self.schema)?;
+        self.id[..4].iter().try_for_each(|b| write!(f, "{:02x}", b))?;
+        write!(f, " priority={}", self.priority)?;
+        if !self.enabled {
+            write!(f, " disabled")?;
+        }
+        Ok(())
+    }
+}
 
-pub trait HasMetalock {
+// Hooks can be kept without resources, ie in an account of their own
+pub trait HasHooks {
     fn get_hooks(&self) -> &MetalockHooks;
     fn get_hooks_mut(&mut self) -> &mut MetalockHooks;
-    //fn get_resources(&self) -> &MetalockResources;
-    //fn get_resources_mut(&mut self) -> &mut MetalockResources;
 }
 
-impl HasMetalock for MetalockTest {
+pub trait HasMetalock: HasHooks {
+    fn get_resources(&self) -> &MetalockResources;
+    fn get_resources_mut(&mut self) -> &mut MetalockResources;
+}
+
+impl HasHooks for MetalockTest {
     fn get_hooks(&self) -> &MetalockHooks { &self.hooks }
     fn get_hooks_mut(&mut self) -> &mut MetalockHooks { &mut self.hooks }
-    //fn get_resources(&self) -> &MetalockResources { &self.resources }
-    //fn get_resources_mut(&mut self) -> &mut MetalockResources { &mut self.resources }
-}
-pub struct Metalock<'a, S: HasMetalock>(pub &'a mut S);
-
-//impl<'a, S: HasMetalock> Metalock<'a, S> {
-//    pub fn get_typed<T: SchemaType + Decode>(&self, name: String) -> Option<T> {
-//        let typ = T::to_schema();
-//        self._get(&ResourceId(typ, name)).map(
-//            |b| Decode::rd_decode(&mut &**b).expect("Could not deserialize")
-//        )
-//    }
-//    pub fn set_typed<T: SchemaType + Encode>(&mut self, name: String, data: T) {
-//        let t = T::to_schema();
-//        let d = data.rd_encode();
-//        self._set(ResourceId(t, name), ResourceDataOrPtr::Data(d), Self::SET_INSERT);
-//    }
-//    pub fn set_with_schema(&mut self, schema: &Schema, name: String, data: RD) {
-//        panic!("set_with_schema")
-//        //assert!(validate_resource_data(&"", schema, &data).is_ok(), "Resources::set: invalid data");
-//        //self._set(ResourceId(schema.encode(), name), data.into(), Self::SET_INSERT | Self::SET_UPDATE);
-//    }
-//
-//    const SET_INSERT: u8 = 1;
-//    const SET_UPDATE: u8 = 2;
-//
-//    fn _set(&mut self, ResourceId(typ, name): ResourceId, data: ResourceDataOrPtr, op: u8) -> SetResult {
-//        if let Some((_, resources)) = self.0.get_resources_mut().iter_mut().find(|r| r.0 == typ) {
-//            if let Some(v) = resources.iter_mut().find(|f| f.0 == name) {
-//                if op & 1 > 0 {
-//                    return SetResult::Replaced(std::mem::replace(&mut v.1, data));
-//                }
-//            }
-//            if op & 2 > 0 {    
-//                resources.push((name, data.into()));
-//                return SetResult::Inserted;
-//            }
-//        } else {
-//            if op & 2 > 0 {    
-//                self.0.get_resources_mut().push((typ, vec![(name, data.into())]));
-//                return SetResult::Inserted;
-//            }
-//        }
-//        SetResult::Noop
-//    }
-//    //pub fn take(&mut self, ResourceId(typ, name): ResourceId) -> Option<ResourceData> {
-//    //    if let Some(idxa) = self.get_resources().iter().position(|r| r.0 == typ) {
-//    //        let d = &mut self.get_resources()[idxa].1;
-//    //        if let Some(idxb) = d.iter().position(|f| f.0 == name) {
-//    //            let o = Some(d.remove(idxb).1);
-//    //            if d.len() == 0 {
-//    //                self.get_resources().remove(idxa);
-//    //            }
-//    //            return o;
-//    //        }
-//    //    }
-//    //    None
-//    //}
-//    //
-//
-//    /*
-//     * Upgrade allows additional fields to be added to a schema,
-//     * for example, if you have the schema:
-//     *
-//     * { members: [{ name: String, age: u8 }] }
-//     *
-//     * You can upgrade it to a compatible schema:
-//     *   
-//     * { members: [{ name: String, age: u8, balance: u64 }] }
-//     *
-//     * And the old key will point to the new key for backwards compatability
-//     */
-//    //pub fn upgrade(&mut self, old_id: ResourceId, new_id: ResourceId) {
-//    //    assert!(
-//    //        schema_is_superset(&old_id.0.to_schema(), &new_id.0.to_schema()),
-//    //        "Resources::upgrade: not superset"
-//    //    );
-//
-//    //    let ptr = ResourceDataOrPtr::Ptr(new_id.clone());
-//    //    let old = self._set(old_id, ptr, Self::SET_UPDATE);
-//    //    let old = match old {
-//    //        SetResult::Replaced(d) => d,
-//    //        _ => panic!("Resources::upgrade: old does not exist")
-//    //    };
-//
-//    //    let r = self._set(new_id, old, Self::SET_INSERT);
-//    //    assert!(r == SetResult::Inserted, "Resources::upgrade: new already exists");
-//    //}
-//
-//    fn _get(&self, id: &ResourceId) -> Option<&Vec<u8>> {
-//        let r = &self.0.get_resources().iter().find(|r| r.0 == id.0)?.1.iter().find(|f| f.0 == id.1)?.1;
-//        match r {
-//            ResourceDataOrPtr::Data(d) => Some(d),
-//            ResourceDataOrPtr::Ptr(p) => self._get(p)
-//        }
-//    }
-//}
+}
+impl HasMetalock for MetalockTest {
+    fn get_resources(&self) -> &MetalockResources { &self.resources }
+    fn get_resources_mut(&mut self) -> &mut MetalockResources { &mut self.resources }
+}
+pub struct Metalock<'a, S>(pub &'a mut S);
 
 
-impl_into!([], ResourceDataOrPtr, ResourceData, |self| ResourceDataOrPtr::Data(self.rd_encode()));
-impl_into!([], ResourceDataOrPtr, ResourceId, |self| ResourceDataOrPtr::Ptr(self));
+/*
+ * Resources
+ */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResourceError {
+    NotFound,
+    Exists,
+    NotSuperset,
+    // Written through the id it was upgraded from
+    Upgraded,
+    Invalid(String),
+}
+
+impl std::fmt::Display for ResourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceError::NotFound => write!(f, "resource not found"),
+            ResourceError::Exists => write!(f, "resource exists"),
+            ResourceError::NotSuperset => write!(f, "resource: not a superset"),
+            ResourceError::Upgraded => write!(f, "resource was upgraded"),
+            ResourceError::Invalid(s) => write!(f, "resource: {}", s),
+        }
+    }
+}
+
+impl<'a, S: HasMetalock> Metalock<'a, S> {
+    pub fn get_typed<T: SchemaType + Decode>(&self, name: &str) -> Option<T> {
+        self.0.get_resources().get_typed(name)
+    }
+    pub fn set_typed<T: SchemaType + Encode>(&mut self, name: &str, data: T) -> std::result::Result<(), ResourceError> {
+        self.0.get_resources_mut().set_typed(name, data)
+    }
+    pub fn upgrade(&mut self, old_id: ResourceId, new_id: ResourceId) -> std::result::Result<(), ResourceError> {
+        self.0.get_resources_mut().upgrade(old_id, new_id)
+    }
+}
+
+impl MetalockResources {
+    pub fn get_typed<T: SchemaType + Decode>(&self, name: &str) -> Option<T> {
+        let data = self.get_data(&ResourceId(T::to_schema(), name.into()))?;
+        T::rd_decode(&mut data.as_ref()).ok()
+    }
+
+    pub fn set_typed<T: SchemaType + Encode>(&mut self, name: &str, data: T) -> std::result::Result<(), ResourceError> {
+        self.set_data(ResourceId(T::to_schema(), name.into()), data.rd_encode())
+    }
+
+    // Encoded with the schema of the id, also if it was upgraded
+    pub fn get_data(&self, id: &ResourceId) -> Option<Vec<u8>> {
+        let (schema, data) = self.resolve(id)?;
+        match *schema == id.0 {
+            true => Some(data.clone()),
+            false => data_convert(schema, &id.0, data).ok()
+        }
+    }
+
+    pub fn set_data(&mut self, id: ResourceId, data: Vec<u8>) -> std::result::Result<(), ResourceError> {
+        match self.find_mut(&id) {
+            Some(ResourceDataOrPtr::Ptr(_)) => Err(ResourceError::Upgraded),
+            Some(r) => {
+                *r = ResourceDataOrPtr::Data(data);
+                Ok(())
+            },
+            None => {
+                self.insert(id, ResourceDataOrPtr::Data(data));
+                Ok(())
+            }
+        }
+    }
+
+    /*
+     * Upgrade allows additional fields to be added to a schema,
+     * for example, if you have the schema:
+     *
+     * { members: [{ name: String, age: u8 }] }
+     *
+     * You can upgrade it to a compatible schema:
+     *   
+     * { members: [{ name: String, age: u8, balance: u64 }] }
+     *
+     * The data is moved to the new id, with default values for the new
+     * fields, and the old id points to the new one for backwards
+     * compatibility. It can still be read, but not written.
+     */
+    pub fn upgrade(&mut self, old_id: ResourceId, new_id: ResourceId) -> std::result::Result<(), ResourceError> {
+        if !schema_is_superset(&mut old_id.0.parser(), &mut new_id.0.parser()) {
+            return Err(ResourceError::NotSuperset);
+        }
+        if self.find(&new_id).is_some() {
+            return Err(ResourceError::Exists);
+        }
+        let data = match self.find(&old_id) {
+            Some(ResourceDataOrPtr::Data(data)) => data,
+            Some(ResourceDataOrPtr::Ptr(_)) => return Err(ResourceError::Upgraded),
+            None => return Err(ResourceError::NotFound)
+        };
+        let data = data_convert(&old_id.0, &new_id.0, data).map_err(ResourceError::Invalid)?;
+        *self.find_mut(&old_id).unwrap() = ResourceDataOrPtr::Ptr(new_id.clone());
+        self.insert(new_id, ResourceDataOrPtr::Data(data));
+        Ok(())
+    }
+
+    // Follows upgrades; these can't form a cycle since the new id can't exist
+    fn resolve<'a>(&'a self, id: &'a ResourceId) -> Option<(&'a Schema, &'a Vec<u8>)> {
+        match self.find(id)? {
+            ResourceDataOrPtr::Data(data) => Some((&id.0, data)),
+            ResourceDataOrPtr::Ptr(ptr) => self.resolve(ptr)
+        }
+    }
+
+    fn find(&self, ResourceId(schema, name): &ResourceId) -> Option<&ResourceDataOrPtr> {
+        let resources = &self.0.iter().find(|r| r.0 == *schema)?.1;
+        resources.iter().find(|r| r.0 == *name).map(|r| &r.1)
+    }
+
+    fn find_mut(&mut self, ResourceId(schema, name): &ResourceId) -> Option<&mut ResourceDataOrPtr> {
+        let resources = &mut self.0.iter_mut().find(|r| r.0 == *schema)?.1;
+        resources.iter_mut().find(|r| r.0 == *name).map(|r| &mut r.1)
+    }
+
+    fn insert(&mut self, ResourceId(schema, name): ResourceId, data: ResourceDataOrPtr) {
+        match self.0.iter_mut().find(|r| r.0 == schema) {
+            Some((_, resources)) => resources.push((name, data)),
+            None => self.0.push((schema, vec![(name, data)]))
+        }
+    }
+}
 
-#[derive(PartialEq, Eq)]
-enum SetResult {
-    Replaced(ResourceDataOrPtr),
-    Inserted,
-    Noop
+impl ResourceStore for MetalockResources {
+    fn get(&self, schema: &Schema, name: &str) -> Option<Vec<u8>> {
+        self.get_data(&ResourceId(schema.clone(), name.into()))
+    }
+    fn set(&mut self, schema: &Schema, name: &str, data: Vec<u8>) -> std::result::Result<(), String> {
+        self.set_data(ResourceId(schema.clone(), name.into()), data).map_err(|e| e.to_string())
+    }
 }
 
 
+impl_into!([], ResourceDataOrPtr, ResourceData, |self| ResourceDataOrPtr::Data(self.rd_encode()));
+impl_into!([], ResourceDataOrPtr, ResourceId, |self| ResourceDataOrPtr::Ptr(self));
+
+
 
 
 /*
  * Hooks
  */
 
-impl<'a, S: HasMetalock> Metalock<'a, S> {
-    pub fn add_hook(&mut self, name: String, bin: Vec<u8>) -> std::result::Result<(), String> {
-
-        // take schema from bin
-        let parser = ParserBuffer::new(&bin);
-        let mut parser = SchemaParser(parser);
-        assert!(parser[0] == tag::FUNCTION::ID);
-        let schema = parser.take_schema();
-        let hook = MetalockHook {
-            name,
-            schema,
-            code: parser.to_vec()
-        };
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookError {
+    // The upload doesn't start with a function schema
+    InvalidSchema,
+    // Refused by the header check
+    Unsupported(String),
+    // The owner has the id registered under another name
+    Exists,
+    NotFound,
+    NotOwner,
+    Disabled,
+    SchemaMismatch,
+    QuotaExceeded(u16),
+    Eval(EvalError),
+}
 
-        let r = self.0.get_hooks().binary_search(&hook);
-        match r {
-            Ok(_) => panic!("Hook exists"),
-            Err(idx) => {
-                self.0.get_hooks_mut().insert(idx, hook);
-                Ok(())
+impl std::fmt::Display for HookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HookError::InvalidSchema => write!(f, "hook: invalid schema"),
+            HookError::Unsupported(s) => write!(f, "hook: unsupported program: {}", s),
+            HookError::Exists => write!(f, "hook exists"),
+            HookError::NotFound => write!(f, "hook not found"),
+            HookError::NotOwner => write!(f, "hook: not owner"),
+            HookError::Disabled => write!(f, "hook disabled"),
+            HookError::SchemaMismatch => write!(f, "hook schema mismatch"),
+            HookError::QuotaExceeded(n) => write!(f, "hook quota exceeded: {}", n),
+            HookError::Eval(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<'a, S: HasHooks> Metalock<'a, S> {
+    /*
+     * Returns the hook id, which identical uploads share: each owner can
+     * register it once, and uploading it again is a noop. An owner's calls
+     * with the id only manage their own registration.
+     */
+    pub fn add_hook(&mut self, owner: Pubkey, name: String, bin: Vec<u8>) -> std::result::Result<[u8; 32], HookError> {
+        let (schema, code) = Self::parse_bin(&bin)?;
+        let id = program_hash(&schema, &code);
+
+        if let Some(hook) = self.registered(&owner, &id) {
+            return match hook.name == name {
+                true => Ok(id),
+                false => Err(HookError::Exists)
+            };
+        }
+        if let Some(quota) = self.0.get_hooks().owner_quota {
+            if self.0.get_hooks().iter().filter(|hook| hook.owner == owner).count() >= quota as usize {
+                return Err(HookError::QuotaExceeded(quota));
             }
         }
+
+        self.insert(MetalockHook { schema, name, priority: 0, id, code, owner, enabled: true });
+        Ok(id)
+    }
+
+    pub fn remove_hook(&mut self, owner: &Pubkey, id: &[u8; 32]) -> std::result::Result<MetalockHook, HookError> {
+        let idx = self.owned(owner, id)?;
+        Ok(self.0.get_hooks_mut().remove(idx))
+    }
+
+    // Upgrade the code in place, keeping name, priority etc. The schema can't change
+    pub fn replace_hook(&mut self, owner: &Pubkey, id: &[u8; 32], bin: Vec<u8>) -> std::result::Result<[u8; 32], HookError> {
+        let idx = self.owned(owner, id)?;
+        let (schema, code) = Self::parse_bin(&bin)?;
+        if schema != self.0.get_hooks()[idx].schema {
+            return Err(HookError::SchemaMismatch);
+        }
+        let new_id = program_hash(&schema, &code);
+        if new_id != *id && self.registered(owner, &new_id).is_some() {
+            return Err(HookError::Exists);
+        }
+        let mut hook = self.0.get_hooks_mut().remove(idx);
+        hook.code = code;
+        hook.id = new_id;
+        self.insert(hook);
+        Ok(new_id)
+    }
+
+    pub fn set_hook_enabled(&mut self, owner: &Pubkey, id: &[u8; 32], enabled: bool) -> std::result::Result<(), HookError> {
+        let idx = self.owned(owner, id)?;
+        self.0.get_hooks_mut()[idx].enabled = enabled;
+        Ok(())
+    }
+
+    pub fn set_hook_priority(&mut self, owner: &Pubkey, id: &[u8; 32], priority: u8) -> std::result::Result<(), HookError> {
+        let idx = self.owned(owner, id)?;
+        let mut hook = self.0.get_hooks_mut().remove(idx);
+        hook.priority = priority;
+        self.insert(hook);
+        Ok(())
+    }
+
+    pub fn set_owner_quota(&mut self, quota: Option<u16>) {
+        self.0.get_hooks_mut().owner_quota = quota;
+    }
+
+    // The first registration of the id, see add_hook
+    pub fn get_hook(&self, id: &[u8; 32]) -> Option<&MetalockHook> {
+        self.0.get_hooks().iter().find(|hook| hook.id == *id)
+    }
+
+    // In call order; MetalockHook displays with its schema
+    pub fn list_hooks(&self) -> impl Iterator<Item=&MetalockHook> {
+        self.0.get_hooks().iter()
+    }
+
+    // Enabled hooks with the name and schema, in order of priority
+    pub fn hooks(&self, schema: &Schema, name: &str) -> impl Iterator<Item=&MetalockHook> {
+        let needle = MetalockHook {
+            schema: schema.clone(),
+            name: name.to_string(),
+            priority: 0,
+            id: Default::default(),
+            code: Default::default(),
+            owner: Default::default(),
+            enabled: false
+        };
+        let idx = self.0.get_hooks().binary_search(&needle).unwrap_or_else(|e| e);
+        self.0.get_hooks()[idx..].iter()
+            .take_while(move |hook| hook.schema == needle.schema && hook.name == needle.name)
+            .filter(|hook| hook.enabled)
     }
 
     pub fn call_hook_with_results<In: SchemaType + Into<RD>, Out: SchemaType>(
@@ -193,30 +384,381 @@ impl<'a, S: HasMetalock> Metalock<'a, S> {
         context: EvaluatorContext,
         name: String,
         input: &In,
-        out_p: PhantomData<Out>
+        _out_p: PhantomData<Out>
     ) -> Vec<std::result::Result<RD, String>> {
-
         let schema = Function::<In, Out>::to_schema();
-        
-        let needle = MetalockHook {
-            schema: schema.clone(),
-            name: name.clone(),
-            code: Default::default()
+        self.hooks(&schema, &name)
+            .map(|hook| Self::run_hook(hook, context.clone(), input.clone().into()).result.map_err(|e| e.to_string()))
+            .collect()
+    }
+
+    // Call a pinned hook, its schema must match In and Out
+    pub fn call_hook_by_id<In: SchemaType + Into<RD>, Out: SchemaType>(
+        &self,
+        context: EvaluatorContext,
+        id: &[u8; 32],
+        input: &In,
+        _out_p: PhantomData<Out>
+    ) -> std::result::Result<RD, HookError> {
+        let hook = self.get_hook(id).ok_or(HookError::NotFound)?;
+        if hook.schema != Function::<In, Out>::to_schema() {
+            return Err(HookError::SchemaMismatch);
+        }
+        // Registrations of the id have the same code, any enabled one will do
+        let hook = self.0.get_hooks().iter().find(|hook| hook.id == *id && hook.enabled).ok_or(HookError::Disabled)?;
+        Self::run_hook(hook, context, input.clone().into()).result.map_err(HookError::Eval)
+    }
+
+    // Each hook gets its own evaluator, so the context's cost_limit is per hook
+    fn run_hook(hook: &MetalockHook, context: EvaluatorContext, input: RD) -> HookRun {
+        let (result, cost) = match Evaluator::load(&mut hook.code.as_ref(), context) {
+            Ok(mut eval) => (eval.try_run(input), eval.cost()),
+            Err(e) => (Err(e), 0)
         };
-        let idx = self.0.get_hooks().binary_search(&needle).unwrap_or_else(|e| e);
+        HookRun { id: hook.id, result, cost }
+    }
+
+    // Schema and code, as uploaded
+    fn parse_bin(bin: &[u8]) -> std::result::Result<(Schema, Vec<u8>), HookError> {
+        if bin.first() != Some(&tag::FUNCTION::ID) {
+            return Err(HookError::InvalidSchema);
+        }
+        let (schema, code) = bin.split_at(schema_len(bin).map_err(|_| HookError::InvalidSchema)?);
+        // Refuse hooks that this build can't run, or whose header misstates the code
+        let mut body = code;
+        Header::decode(&mut body)
+            .and_then(|header| header.check().and_then(|_| header.verify(body)))
+            .map_err(HookError::Unsupported)?;
+        Ok((Schema(schema.to_vec()), code.to_vec()))
+    }
+
+    fn insert(&mut self, hook: MetalockHook) {
+        let idx = self.0.get_hooks().binary_search(&hook).unwrap_or_else(|e| e);
+        self.0.get_hooks_mut().insert(idx, hook);
+    }
 
-        let mut results = vec![];
+    fn registered(&self, owner: &Pubkey, id: &[u8; 32]) -> Option<&MetalockHook> {
+        self.0.get_hooks().iter().find(|hook| hook.id == *id && hook.owner == *owner)
+    }
+
+    fn owned(&self, owner: &Pubkey, id: &[u8; 32]) -> std::result::Result<usize, HookError> {
+        let hooks = self.0.get_hooks();
+        match hooks.iter().position(|hook| hook.id == *id && hook.owner == *owner) {
+            Some(idx) => Ok(idx),
+            None if hooks.iter().any(|hook| hook.id == *id) => Err(HookError::NotOwner),
+            None => Err(HookError::NotFound)
+        }
+    }
+}
+
+
+/*
+ * Aggregation of the hooks with a name into one decision. Hooks run in order
+ * of priority and stop as soon as the outcome is known. A failing hook
+ * doesn't affect the others; it counts as false for all_hooks, and is
+ * otherwise skipped. The report has every hook that ran.
+ */
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HookRun {
+    pub id: [u8; 32],
+    pub result: std::result::Result<RD, EvalError>,
+    pub cost: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HookReport<T> {
+    pub value: T,
+    // The hook that determined the value, if any single one did
+    pub decided_by: Option<[u8; 32]>,
+    pub runs: Vec<HookRun>,
+}
+
+enum Step {
+    Continue,
+    Decide,
+    Stop,
+}
+
+impl<'a, S: HasHooks> Metalock<'a, S> {
+    // True if every hook returns true, or there are none
+    pub fn all_hooks<In: SchemaType + Into<RD>>(&self, context: EvaluatorContext, name: &str, input: &In) -> HookReport<bool> {
+        self.aggregate::<In, bool, _>(context, name, input, true, |v, r| match r {
+            Ok(rd) if rd._as::<bool>() => Step::Continue,
+            _ => { *v = false; Step::Stop }
+        })
+    }
+
+    pub fn any_hook<In: SchemaType + Into<RD>>(&self, context: EvaluatorContext, name: &str, input: &In) -> HookReport<bool> {
+        self.aggregate::<In, bool, _>(context, name, input, false, |v, r| match r {
+            Ok(rd) if rd._as::<bool>() => { *v = true; Step::Stop },
+            _ => Step::Continue
+        })
+    }
+
+    pub fn first_some_hook<In: SchemaType + Into<RD>, T: SchemaType + FromRD>(
+        &self,
+        context: EvaluatorContext,
+        name: &str,
+        input: &In
+    ) -> HookReport<Option<T>> {
+        self.aggregate::<In, Option<T>, _>(context, name, input, None, |v, r| match r {
+            Ok(rd) => {
+                *v = rd._as::<Option<T>>();
+                if v.is_some() { Step::Stop } else { Step::Continue }
+            },
+            _ => Step::Continue
+        })
+    }
 
-        for hook in &self.0.get_hooks()[idx..] {
-            if hook.schema != schema || hook.name != name {
-                break;
-            } else {
-                let mut eval = Evaluator::new(&mut hook.code.as_ref(), context.clone());
-                let r = eval.run(input.clone().into());
-                results.push(Ok(r));
+    // Saturating
+    pub fn sum_hooks<In: SchemaType + Into<RD>>(&self, context: EvaluatorContext, name: &str, input: &In) -> HookReport<u64> {
+        self.aggregate::<In, u64, _>(context, name, input, 0, |v, r| {
+            if let Ok(rd) = r {
+                *v = v.saturating_add(rd._as());
+            }
+            Step::Continue
+        })
+    }
+
+    pub fn max_hook<In: SchemaType + Into<RD>>(&self, context: EvaluatorContext, name: &str, input: &In) -> HookReport<Option<u64>> {
+        self.aggregate::<In, u64, _>(context, name, input, None, |v, r| match r {
+            Ok(rd) if Some(rd._as::<u64>()) > *v => { *v = Some(rd._as()); Step::Decide },
+            _ => Step::Continue
+        })
+    }
+
+    fn aggregate<In: SchemaType + Into<RD>, Out: SchemaType, T>(
+        &self,
+        context: EvaluatorContext,
+        name: &str,
+        input: &In,
+        init: T,
+        mut step: impl FnMut(&mut T, &std::result::Result<RD, EvalError>) -> Step
+    ) -> HookReport<T> {
+        let mut report = HookReport { value: init, decided_by: None, runs: vec![] };
+        for hook in self.hooks(&Function::<In, Out>::to_schema(), name) {
+            let run = Self::run_hook(hook, context.clone(), input.clone().into());
+            let s = step(&mut report.value, &run.result);
+            report.runs.push(run);
+            match s {
+                Step::Continue => {},
+                Step::Decide => report.decided_by = Some(hook.id),
+                Step::Stop => {
+                    report.decided_by = Some(hook.id);
+                    break;
+                }
             }
         }
+        report
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::*;
+    use metalock_core::vm::expr::RR;
+    use crate::program::{Program, IntoProgram};
+    use crate::compile::OpEval;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn bin<I: SchemaType, O: SchemaType>(prog: &Program<I, O>) -> Vec<u8> {
+        [prog.schema.0.clone(), prog.clone().compile()].concat()
+    }
+
+    #[test]
+    fn test_hook_id() {
+        let mut state = MetalockTest::default();
+        let mut m = Metalock(&mut state);
+        let owner = Pubkey::new_unique();
+        let prog = (|n: RR<u8>| n.add(1)).to_program();
+
+        let id = m.add_hook(owner, "inc".into(), bin(&prog)).unwrap();
+        assert_eq!(id, prog.hash());
+        // Identical upload is deduplicated
+        assert_eq!(m.add_hook(owner, "inc".into(), bin(&prog)), Ok(id));
+        assert_eq!(m.add_hook(owner, "other".into(), bin(&prog)), Err(HookError::Exists));
+        assert_eq!(m.0.get_hooks().len(), 1);
+        assert_eq!(m.get_hook(&id).unwrap().name(), "inc");
+
+        // Another owner can register the same upload, and only manages their own
+        let other = Pubkey::new_unique();
+        assert_eq!(m.add_hook(other, "other".into(), bin(&prog)), Ok(id));
+        assert_eq!(m.0.get_hooks().len(), 2);
+        m.set_hook_enabled(&owner, &id, false).unwrap();
+        let r = m.call_hook_by_id(Default::default(), &id, &1u8, PhantomData::<u8>);
+        assert_eq!(r, Ok(2u8.into()));
+        assert_eq!(m.remove_hook(&other, &id).unwrap().owner(), &other);
+        assert_eq!(m.remove_hook(&other, &id).err(), Some(HookError::NotOwner));
+        let r = m.call_hook_by_id(Default::default(), &id, &1u8, PhantomData::<u8>);
+        assert_eq!(r, Err(HookError::Disabled));
+        m.set_hook_enabled(&owner, &id, true).unwrap();
+
+        let r = m.call_hook_by_id(Default::default(), &id, &1u8, PhantomData::<u8>);
+        assert_eq!(r, Ok(2u8.into()));
+        let r = m.call_hook_by_id(Default::default(), &id, &1u8, PhantomData::<bool>);
+        assert_eq!(r, Err(HookError::SchemaMismatch));
+        let r = m.call_hook_by_id(Default::default(), &[0; 32], &1u8, PhantomData::<u8>);
+        assert_eq!(r, Err(HookError::NotFound));
+    }
+
+    #[test]
+    fn test_hook_aggregate() {
+        let mut state = MetalockTest::default();
+        let mut m = Metalock(&mut state);
+        let owner = Pubkey::new_unique();
+        let ctx = || EvaluatorContext::default();
+        let mut add = |name: &str, bin: Vec<u8>| m.add_hook(owner, name.into(), bin).unwrap();
+
+        // Hooks with the same priority run in id order, so set it explicitly
+        let lt = |k: u64| bin(&(move |n: RR<u64>| n.less_than(k)).to_program());
+        let ids = [add("check", lt(10)), add("check", lt(5)), add("check", lt(100))];
+        let fee = |k: u64| bin(&(move |n: RR<u64>| n.add(k)).to_program());
+        let fees = [add("fee", fee(1)), add("fee", fee(u64::MAX)), add("fee", fee(2))];
+        let some = |k: u64| bin(&(move |n: RR<u64>| m_if(n.clone().equals(k), n)).to_program());
+        let somes = [add("some", some(1)), add("some", some(2))];
+        for (i, id) in ids.iter().chain(&fees).chain(&somes).enumerate() {
+            m.set_hook_priority(&owner, id, i as u8).unwrap();
+        }
+
+        // Stops at the first false
+        let r = m.all_hooks(ctx(), "check", &7u64);
+        assert_eq!((r.value, r.decided_by, r.runs.len()), (false, Some(ids[1]), 2));
+        let r = m.all_hooks(ctx(), "check", &1u64);
+        assert_eq!((r.value, r.decided_by, r.runs.len()), (true, None, 3));
+        assert!(m.all_hooks(ctx(), "none", &1u64).value);
+
+        let r = m.any_hook(ctx(), "check", &50u64);
+        assert_eq!((r.value, r.decided_by, r.runs.len()), (true, Some(ids[2]), 3));
+        assert!(!m.any_hook(ctx(), "check", &500u64).value);
+
+        // Overflow fails that hook only
+        let r = m.sum_hooks(ctx(), "fee", &1u64);
+        assert_eq!((r.value, r.decided_by), (5, None));
+        assert!(r.runs[1].result.is_err());
+        let r = m.max_hook(ctx(), "fee", &1u64);
+        assert_eq!((r.value, r.decided_by), (Some(3), Some(fees[2])));
+
+        let r = m.first_some_hook::<u64, u64>(ctx(), "some", &2u64);
+        assert_eq!((r.value, r.decided_by, r.runs.len()), (Some(2), Some(somes[1]), 2));
+        assert_eq!(m.first_some_hook::<u64, u64>(ctx(), "some", &3u64).value, None);
+
+        // Per hook budget
+        let ctx = EvaluatorContext { cost_limit: Some(1), ..Default::default() };
+        let r = m.all_hooks(ctx, "check", &1u64);
+        assert!(!r.value);
+        assert!(matches!(r.runs[0].result, Err(EvalError::Limit(_))));
+        assert!(r.runs[0].cost > 1);
+    }
+
+    #[test]
+    fn test_hook_registry() {
+        let mut state = MetalockTest::default();
+        let mut m = Metalock(&mut state);
+        let (alice, bob) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let add = |k: u8| bin(&(move |n: RR<u8>| n.add(k)).to_program());
+        let call = |m: &Metalock<MetalockTest>| {
+            m.call_hook_with_results(Default::default(), "h".into(), &1u8, PhantomData::<u8>)
+        };
+
+        assert_eq!(m.add_hook(alice, "h".into(), vec![]), Err(HookError::InvalidSchema));
+        assert_eq!(m.add_hook(alice, "h".into(), add(1)[..2].to_vec()), Err(HookError::InvalidSchema));
+        assert_eq!(m.add_hook(alice, "h".into(), add(1)[1..].to_vec()), Err(HookError::InvalidSchema));
+        let headerless = [&add(1)[..3], &add(1)[3+Header::SIZE..]].concat();
+        assert!(matches!(m.add_hook(alice, "h".into(), headerless), Err(HookError::Unsupported(_))));
+        let mut understated = add(1);
+        understated[3+2..3+34].fill(0);
+        assert!(matches!(m.add_hook(alice, "h".into(), understated), Err(HookError::Unsupported(_))));
+
+        let a = m.add_hook(alice, "h".into(), add(1)).unwrap();
+        let b = m.add_hook(bob, "h".into(), add(2)).unwrap();
+        m.set_hook_priority(&alice, &a, 2).unwrap();
+        m.set_hook_priority(&bob, &b, 1).unwrap();
+        assert_eq!(call(&m), vec![Ok(3u8.into()), Ok(2u8.into())]);
+        assert!(str::starts_with(&m.list_hooks().next().unwrap().to_string(), "h: Function<u8, u8> id="));
+
+        // Owners only
+        assert_eq!(m.set_hook_enabled(&alice, &b, false), Err(HookError::NotOwner));
+        m.set_hook_enabled(&bob, &b, false).unwrap();
+        assert_eq!(call(&m), vec![Ok(2u8.into())]);
+        assert!(str::ends_with(&m.list_hooks().next().unwrap().to_string(), "priority=1 disabled"));
+        let r = m.call_hook_by_id(Default::default(), &b, &1u8, PhantomData::<u8>);
+        assert_eq!(r, Err(HookError::Disabled));
+
+        // Upgrade in place
+        let a2 = m.replace_hook(&alice, &a, add(10)).unwrap();
+        assert!(m.get_hook(&a).is_none());
+        assert_eq!(m.get_hook(&a2).unwrap().priority(), 2);
+        assert_eq!(call(&m), vec![Ok(11u8.into())]);
+        let other = bin(&(|n: RR<u8>| n.equals(1)).to_program());
+        assert_eq!(m.replace_hook(&alice, &a2, other), Err(HookError::SchemaMismatch));
+
+        // Quotas
+        m.set_owner_quota(Some(1));
+        assert_eq!(m.add_hook(alice, "h".into(), add(3)), Err(HookError::QuotaExceeded(1)));
+        assert_eq!(m.remove_hook(&alice, &a2).unwrap().id(), &a2);
+        assert_eq!(m.remove_hook(&alice, &a2).err(), Some(HookError::NotFound));
+        assert!(m.add_hook(alice, "h".into(), add(3)).is_ok());
+    }
+
+    #[test]
+    fn test_resources() {
+        type Old = Vec<(u8, String)>;
+        type New = Vec<(u8, String, u64)>;
+        let mut state = MetalockTest::default();
+        let mut m = Metalock(&mut state);
+        let id = |s: Schema| ResourceId(s, "members".into());
+        let other = |s: Schema| ResourceId(s, "other".into());
+
+        assert_eq!(m.get_typed::<Old>("members"), None);
+        m.set_typed::<Old>("members", vec![(1, "a".into())]).unwrap();
+        assert_eq!(m.get_typed::<Old>("members"), Some(vec![(1, "a".into())]));
+        // Same name with another schema is another resource
+        assert_eq!(m.get_typed::<u8>("members"), None);
+
+        assert_eq!(m.upgrade(id(Old::to_schema()), id(u8::to_schema())), Err(ResourceError::NotSuperset));
+        assert_eq!(m.upgrade(other(Old::to_schema()), other(New::to_schema())), Err(ResourceError::NotFound));
+        m.upgrade(id(Old::to_schema()), id(New::to_schema())).unwrap();
+        assert_eq!(m.get_typed::<New>("members"), Some(vec![(1, "a".into(), 0)]));
+        assert_eq!(m.upgrade(id(Old::to_schema()), id(New::to_schema())), Err(ResourceError::Exists));
+
+        // The old id reads through to the new data, but can't be written
+        m.set_typed::<New>("members", vec![(1, "a".into(), 5), (2, "b".into(), 6)]).unwrap();
+        assert_eq!(m.get_typed::<Old>("members"), Some(vec![(1, "a".into()), (2, "b".into())]));
+        assert_eq!(m.set_typed::<Old>("members", vec![]), Err(ResourceError::Upgraded));
+    }
+
+    #[test]
+    fn test_resource_ops() {
+        let store = Rc::new(RefCell::new(MetalockResources::default()));
+        store.borrow_mut().set_typed("x", 1u64).unwrap();
+        let ctx = |writable| EvaluatorContext {
+            resources: Some(store.clone()),
+            resources_writable: writable,
+            ..Default::default()
+        };
+
+        let mut get = resource::<u64>("x".to_string());
+        assert_eq!(get.eval_with_context(ctx(false), usize::MAX), Some(1u64).into());
+        let mut get = resource::<u64>("y".to_string());
+        assert_eq!(get.eval_with_context(ctx(false), usize::MAX), None::<u64>.into());
+
+        let mut set = set_resource("y".to_string(), 2u64).then(resource::<u64>("y".to_string()));
+        assert_eq!(set.eval_with_context(ctx(true), usize::MAX), Some(2u64).into());
+        assert_eq!(store.borrow().get_typed::<u64>("y"), Some(2));
+
+        let mut set = try_(set_resource("y".to_string(), 3u64));
+        assert_eq!(set.eval_with_context(ctx(false), usize::MAX), None::<()>.into());
+        let mut get = try_(resource::<u64>("x".to_string()));
+        assert_eq!(get.eval_with_context(Default::default(), usize::MAX), None::<Option<u64>>.into());
+        assert_eq!(store.borrow().get_typed::<u64>("y"), Some(2));
 
-        results
+        // A failed try_ doesn't roll back writes made before the failure
+        let mut set = try_(set_resource("y".to_string(), 4u64).then(panic("after the write")));
+        assert_eq!(set.eval_with_context(ctx(true), usize::MAX), None::<()>.into());
+        assert_eq!(store.borrow().get_typed::<u64>("y"), Some(4));
     }
 }
@@ -0,0 +1,360 @@
+
+use std::fmt::Write;
+
+use metalock_core::internal::*;
+
+
+/*
+ * Client types for schemas, so that clients can build hook inputs without
+ * hand coding the encoding:
+ *
+ *   - Anchor IDL type definitions, for the "types" section of an IDL
+ *   - TypeScript declarations, with an encode function per type
+ *   - An encoder spec, describing the byte layout for a generic encoder
+ *
+ * Tuples become named types with fields _0, _1, ..; nested ones are named
+ * after the root type with a counter, ie Input, Input1, Input2. () becomes an
+ * empty struct. The IDL gives the shape of a value only, it is not borsh
+ * compatible: borsh has u32 lengths where the encoding below has u16, so hook
+ * input passed as raw bytes should be encoded with the encoder.
+ *
+ * The encoding is that of Encode:
+ *
+ *   u8 .. u128    little endian
+ *   bool          1 byte, 0 or 1
+ *   String        u16 length, utf8 bytes
+ *   Buffer        u16 length, bytes
+ *   [u8; 32]      32 bytes
+ *   Option<T>     1 byte, 0 or 1, then T if 1
+ *   Vec<T>        u16 count, then each T
+ *   (A, B, ..)    A, B, .. in order
+ *   ()            nothing
+ */
+
+enum Ty {
+    Unit,
+    Int(&'static str, u8),
+    Bool,
+    String,
+    Buffer,
+    Buf32,
+    Option(Box<Ty>),
+    List(Box<Ty>),
+    Tuple(Vec<Ty>),
+    Function(Box<Ty>, Box<Ty>),
+}
+
+impl Ty {
+    fn parse(schema: &Schema) -> Result<Ty, String> {
+        let mut buf = schema.0.as_slice();
+        let ty = Ty::take(&mut buf)?;
+        match buf.is_empty() {
+            true => Ok(ty),
+            false => Err("trailing bytes in schema".into())
+        }
+    }
+
+    fn take(buf: &mut &[u8]) -> Result<Ty, String> {
+        let (&t, rest) = buf.split_first().ok_or("unexpected end of schema")?;
+        *buf = rest;
+        Ok(match t {
+            tag::UNIT::ID => Ty::Unit,
+            tag::U8::ID => Ty::Int("u8", 1),
+            tag::U16::ID => Ty::Int("u16", 2),
+            tag::U32::ID => Ty::Int("u32", 4),
+            tag::U64::ID => Ty::Int("u64", 8),
+            tag::U128::ID => Ty::Int("u128", 16),
+            tag::BOOL::ID => Ty::Bool,
+            tag::STRING::ID => Ty::String,
+            tag::BUFFER::ID => Ty::Buffer,
+            tag::BUF32::ID => Ty::Buf32,
+            tag::OPTION::ID => Ty::Option(Ty::take(buf)?.into()),
+            tag::LIST::ID => Ty::List(Ty::take(buf)?.into()),
+            tag::TUPLE::ID => {
+                if buf.len() < 3 {
+                    return Err("unexpected end of schema".into());
+                }
+                let n = buf[0];
+                *buf = &buf[3..];
+                Ty::Tuple((0..n).map(|_| Ty::take(buf)).collect::<Result<_, _>>()?)
+            },
+            tag::FUNCTION::ID => Ty::Function(Ty::take(buf)?.into(), Ty::take(buf)?.into()),
+            t => return Err(format!("unsupported schema tag {}", t)),
+        })
+    }
+}
+
+
+#[derive(Default)]
+pub struct TypeGen {
+    names: Vec<String>,
+    idl: Vec<String>,
+    ts: Vec<String>,
+    encoders: Vec<String>,
+}
+
+impl TypeGen {
+    // A named type for values of the schema
+    pub fn add(&mut self, name: &str, schema: &Schema) -> Result<(), String> {
+        self.add_ty(name, &Ty::parse(schema)?)
+    }
+
+    // {name}Input and {name}Output, for a hook with a Function schema
+    pub fn add_hook(&mut self, name: &str, schema: &Schema) -> Result<(), String> {
+        match Ty::parse(schema)? {
+            Ty::Function(input, output) => {
+                self.add_ty(&format!("{}Input", name), &input)?;
+                self.add_ty(&format!("{}Output", name), &output)
+            },
+            _ => Err(format!("not a function: {}", schema))
+        }
+    }
+
+    // JSON array, for the "types" section of an Anchor IDL
+    pub fn idl_types(&self) -> String {
+        format!("[{}]", self.idl.join(","))
+    }
+
+    // Declarations and encode functions, with the helpers they use
+    pub fn typescript(&self) -> String {
+        [TS_HELPERS.to_string(), self.ts.join(""), self.encoders.join("\n")].join("\n")
+    }
+
+    fn add_ty(&mut self, name: &str, ty: &Ty) -> Result<(), String> {
+        let mut chars = name.chars();
+        if !chars.next().is_some_and(|c| c.is_ascii_alphabetic()) || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid type name: {:?}", name));
+        }
+        let (idl_len, ts_len) = (self.idl.len(), self.ts.len());
+        let mut names = vec![name.to_string()];
+        let r = match ty {
+            Ty::Tuple(items) => self.tuple(name, items, &mut names),
+            Ty::Unit => {
+                self.define(name, vec![], "null".into());
+                Ok(())
+            },
+            ty => self.walk(ty, &mut names).map(|(idl, ts)| {
+                self.idl.push(format!(r#"{{"name":"{}","type":{{"kind":"type","alias":{}}}}}"#, name, idl));
+                self.ts.push(format!("export type {} = {};\n", name, ts));
+            })
+        };
+        let r = r.and_then(|_| match names.iter().find(|n| self.names.contains(n)) {
+            Some(dup) => Err(format!("duplicate type name: {}", dup)),
+            None => Ok(())
+        });
+        if r.is_err() {
+            self.idl.truncate(idl_len);
+            self.ts.truncate(ts_len);
+            return r;
+        }
+        self.names.extend(names);
+
+        let mut body = String::new();
+        ts_encode(ty, "v", 1, &mut 0, &mut body);
+        self.encoders.push(format!(
+            "export function encode{0}(v: {0}): Uint8Array {{\n  const out: number[] = [];\n{1}  return Uint8Array.from(out);\n}}\n",
+            name, body
+        ));
+        Ok(())
+    }
+
+    // IDL and TypeScript types of ty, adding definitions for tuples. names
+    // starts with the root type
+    fn walk(&mut self, ty: &Ty, names: &mut Vec<String>) -> Result<(String, String), String> {
+        Ok(match ty {
+            Ty::Unit => {
+                let name = format!("{}{}", names[0], names.len());
+                names.push(name.clone());
+                self.define(&name, vec![], "null".into());
+                (format!(r#"{{"defined":{{"name":"{}"}}}}"#, name), name)
+            },
+            Ty::Int(t, n) => (format!(r#""{}""#, t), if *n > 4 { "bigint" } else { "number" }.into()),
+            Ty::Bool => (r#""bool""#.into(), "boolean".into()),
+            Ty::String => (r#""string""#.into(), "string".into()),
+            Ty::Buffer => (r#""bytes""#.into(), "Uint8Array".into()),
+            Ty::Buf32 => (r#"{"array":["u8",32]}"#.into(), "Uint8Array".into()),
+            Ty::Option(t) => {
+                let (idl, ts) = self.walk(t, names)?;
+                (format!(r#"{{"option":{}}}"#, idl), format!("{} | null", ts))
+            },
+            Ty::List(t) => {
+                let (idl, ts) = self.walk(t, names)?;
+                let ts = if ts.contains(' ') { format!("({})[]", ts) } else { format!("{}[]", ts) };
+                (format!(r#"{{"vec":{}}}"#, idl), ts)
+            },
+            Ty::Tuple(items) => {
+                let name = format!("{}{}", names[0], names.len());
+                names.push(name.clone());
+                self.tuple(&name, items, names)?;
+                (format!(r#"{{"defined":{{"name":"{}"}}}}"#, name), name)
+            },
+            Ty::Function(..) => return Err("function values are not supported".into()),
+        })
+    }
+
+    fn tuple(&mut self, name: &str, items: &[Ty], names: &mut Vec<String>) -> Result<(), String> {
+        let (idl, ts): (Vec<_>, Vec<_>) = items.iter()
+            .map(|t| self.walk(t, names))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter().unzip();
+        self.define(name, idl, format!("[{}]", ts.join(", ")));
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, fields: Vec<String>, ts: String) {
+        let fields = fields.iter().enumerate()
+            .map(|(i, idl)| format!(r#"{{"name":"_{}","type":{}}}"#, i, idl))
+            .collect::<Vec<_>>();
+        self.idl.push(format!(r#"{{"name":"{}","type":{{"kind":"struct","fields":[{}]}}}}"#, name, fields.join(",")));
+        self.ts.push(format!("export type {} = {};\n", name, ts));
+    }
+}
+
+
+const TS_HELPERS: &str = "\
+function writeInt(out: number[], v: number | bigint, bytes: number) {
+  let n = BigInt(v);
+  if (n < 0n || n >> BigInt(8 * bytes) > 0n) throw new RangeError(`out of range for ${bytes} bytes: ${v}`);
+  for (let i = 0; i < bytes; i++) {
+    out.push(Number(n & 0xffn));
+    n >>= 8n;
+  }
+}
+
+function writeBytes(out: number[], b: Uint8Array) {
+  writeInt(out, b.length, 2);
+  out.push(...b);
+}
+";
+
+// Statements writing the value of the expression e to out
+fn ts_encode(ty: &Ty, e: &str, indent: usize, vars: &mut usize, out: &mut String) {
+    let pad = "  ".repeat(indent);
+    let mut var = || {
+        *vars += 1;
+        format!("v{}", vars)
+    };
+    match ty {
+        Ty::Unit => {},
+        Ty::Int(_, n) => writeln!(out, "{}writeInt(out, {}, {});", pad, e, n).unwrap(),
+        Ty::Bool => writeln!(out, "{}out.push({} ? 1 : 0);", pad, e).unwrap(),
+        Ty::String => writeln!(out, "{}writeBytes(out, new TextEncoder().encode({}));", pad, e).unwrap(),
+        Ty::Buffer => writeln!(out, "{}writeBytes(out, {});", pad, e).unwrap(),
+        Ty::Buf32 => {
+            writeln!(out, "{}if ({}.length !== 32) throw new RangeError(\"expected 32 bytes\");", pad, e).unwrap();
+            writeln!(out, "{}out.push(...{});", pad, e).unwrap();
+        },
+        Ty::Option(t) => {
+            let v = var();
+            writeln!(out, "{}const {} = {};", pad, v, e).unwrap();
+            writeln!(out, "{}out.push({} === null ? 0 : 1);", pad, v).unwrap();
+            writeln!(out, "{}if ({} !== null) {{", pad, v).unwrap();
+            ts_encode(t, &v, indent + 1, vars, out);
+            writeln!(out, "{}}}", pad).unwrap();
+        },
+        Ty::List(t) => {
+            let v = var();
+            writeln!(out, "{}writeInt(out, {}.length, 2);", pad, e).unwrap();
+            writeln!(out, "{}for (const {} of {}) {{", pad, v, e).unwrap();
+            ts_encode(t, &v, indent + 1, vars, out);
+            writeln!(out, "{}}}", pad).unwrap();
+        },
+        Ty::Tuple(items) => {
+            for (i, t) in items.iter().enumerate() {
+                ts_encode(t, &format!("{}[{}]", e, i), indent, vars, out);
+            }
+        },
+        // Refused by TypeGen::walk
+        Ty::Function(..) => unreachable!(),
+    }
+}
+
+
+/*
+ * Encoder spec, as JSON: "unit", "u8" .. "u128", "bool", "string",
+ * "buffer", "buf32", {"option": T}, {"vec": T} and {"tuple": [T, ..]}
+ */
+pub fn encoder_spec(schema: &Schema) -> Result<String, String> {
+    fn spec(ty: &Ty) -> Result<String, String> {
+        Ok(match ty {
+            Ty::Unit => r#""unit""#.into(),
+            Ty::Int(t, _) => format!(r#""{}""#, t),
+            Ty::Bool => r#""bool""#.into(),
+            Ty::String => r#""string""#.into(),
+            Ty::Buffer => r#""buffer""#.into(),
+            Ty::Buf32 => r#""buf32""#.into(),
+            Ty::Option(t) => format!(r#"{{"option":{}}}"#, spec(t)?),
+            Ty::List(t) => format!(r#"{{"vec":{}}}"#, spec(t)?),
+            Ty::Tuple(items) => format!(r#"{{"tuple":[{}]}}"#, items.iter().map(spec).collect::<Result<Vec<_>, _>>()?.join(",")),
+            Ty::Function(..) => return Err("function values are not supported".into()),
+        })
+    }
+    spec(&Ty::parse(schema)?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metalock_core::vm::expr::Function;
+
+    type Input = (u64, Vec<(String, Option<u8>)>, [u8; 32]);
+
+    #[test]
+    fn test_type_gen() {
+        let mut gen = TypeGen::default();
+        gen.add_hook("Transfer", &Function::<Input, bool>::to_schema()).unwrap();
+        gen.add("Memo", &Option::<String>::to_schema()).unwrap();
+
+        assert_eq!(gen.idl_types(), [
+            r#"[{"name":"TransferInput1","type":{"kind":"struct","fields":[{"name":"_0","type":"string"},{"name":"_1","type":{"option":"u8"}}]}}"#,
+            r#"{"name":"TransferInput","type":{"kind":"struct","fields":[{"name":"_0","type":"u64"},{"name":"_1","type":{"vec":{"defined":{"name":"TransferInput1"}}}},{"name":"_2","type":{"array":["u8",32]}}]}}"#,
+            r#"{"name":"TransferOutput","type":{"kind":"type","alias":"bool"}}"#,
+            r#"{"name":"Memo","type":{"kind":"type","alias":{"option":"string"}}}]"#,
+        ].join(","));
+
+        // () is an empty struct, also nested
+        let mut units = TypeGen::default();
+        units.add("Done", &<()>::to_schema()).unwrap();
+        units.add("Maybe", &Option::<()>::to_schema()).unwrap();
+        assert_eq!(units.idl_types(), [
+            r#"[{"name":"Done","type":{"kind":"struct","fields":[]}}"#,
+            r#"{"name":"Maybe1","type":{"kind":"struct","fields":[]}}"#,
+            r#"{"name":"Maybe","type":{"kind":"type","alias":{"option":{"defined":{"name":"Maybe1"}}}}}]"#,
+        ].join(","));
+        assert!(units.typescript().contains("export type Maybe = Maybe1 | null;\n"));
+
+        let ts = gen.typescript();
+        assert!(ts.contains("export type TransferInput1 = [string, number | null];\n"));
+        assert!(ts.contains("export type TransferInput = [bigint, TransferInput1[], Uint8Array];\n"));
+        assert!(ts.contains("export type Memo = string | null;\n"));
+        assert!(ts.contains(&[
+            "export function encodeTransferInput(v: TransferInput): Uint8Array {",
+            "  const out: number[] = [];",
+            "  writeInt(out, v[0], 8);",
+            "  writeInt(out, v[1].length, 2);",
+            "  for (const v1 of v[1]) {",
+            "    writeBytes(out, new TextEncoder().encode(v1[0]));",
+            "    const v2 = v1[1];",
+            "    out.push(v2 === null ? 0 : 1);",
+            "    if (v2 !== null) {",
+            "      writeInt(out, v2, 1);",
+            "    }",
+            "  }",
+        ].join("\n")));
+
+        assert_eq!(gen.add("Memo", &u8::to_schema()), Err("duplicate type name: Memo".into()));
+        assert_eq!(gen.add("TransferInput", &Input::to_schema()), Err("duplicate type name: TransferInput".into()));
+        assert_eq!(gen.idl_types().matches("TransferInput1\"").count(), 2);
+        assert!(gen.add("1x", &u8::to_schema()).is_err());
+        assert!(gen.add_hook("Memo2", &u8::to_schema()).is_err());
+    }
+
+    #[test]
+    fn test_encoder_spec() {
+        let spec = encoder_spec(&Input::to_schema()).unwrap();
+        assert_eq!(spec, r#"{"tuple":["u64",{"vec":{"tuple":["string",{"option":"u8"}]}},"buf32"]}"#);
+        assert_eq!(encoder_spec(&<()>::to_schema()), Ok(r#""unit""#.into()));
+        assert!(encoder_spec(&Function::<u8, u8>::to_schema()).is_err());
+    }
+}
@@ -4,5 +4,8 @@ pub mod api;
 pub mod profile;
 pub mod program;
 pub mod frontend;
+pub mod idl;
+#[cfg(feature = "anchor")]
+pub mod anchor;
 mod compile;
 mod native;
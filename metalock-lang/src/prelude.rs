@@ -6,7 +6,7 @@ pub use crate::frontend::*;
 pub use crate::compile::OpTreeImpl;
 
 pub use metalock_core::internal::*;
-pub use metalock_core::vm::eval::{Evaluator, EvaluatorContext};
+pub use metalock_core::vm::eval::{Evaluator, EvaluatorContext, EvalError};
 pub use metalock_core::vm::expr::{RR, Function};
 
 pub use solana_program::pubkey::Pubkey;
@@ -1,9 +1,11 @@
-use std::{marker::PhantomData, usize};
+use std::{collections::BTreeMap, marker::PhantomData, usize};
 
 
 use metalock_core::internal::*;
 use metalock_core::vm::eval::*;
 use metalock_core::vm::expr::*;
+use metalock_core::vm::header::*;
+use metalock_core::vm::verify::verify_function;
 
 use crate::compile::*;
 
@@ -15,30 +17,63 @@ pub struct Program<Input, Output> {
     pub _p: PhantomData<Input>
 }
 
-impl<Input: SchemaType + Into<RD>, Output: SchemaType> Program<Input, Output> {
+impl<Input: SchemaType, Output: SchemaType> Program<Input, Output> {
     pub fn from<A: IntoProgram<Input, Output>>(a: A) -> Self {
-        let var = Var(VarId::new()).rr();
+        let var = Var(VarId::from(0)).rr();
         let expr = a.build(var);
 
         let schema = Function::<Input, Output>::to_schema();
         Program { schema, expr, _p: Default::default() }
     }
 
+    // Header and code, for the latest format version
     pub fn compile(&mut self) -> Vec<u8> {
-        let mut ctx = EncodeContext::new();
-        self.expr.op_encode(&mut ctx).join()
+        self.compile_version(VERSION).unwrap_or_else(|e| panic!("compile: {}", e))
+    }
+
+    // Fails if the program uses opcodes that the version doesn't have
+    pub fn compile_version(&mut self, version: u8) -> Result<Vec<u8>, String> {
+        let (mut tree, var_slots) = self.encode()?;
+        let code = tree.join();
+        let header = Header::build(version, &code, var_slots)?;
+        Ok([header.encode(), code].concat())
     }
 
     pub fn op_tree(&mut self) -> OpTree {
-        let mut ctx = EncodeContext::new();
-        self.expr.op_encode(&mut ctx)
+        self.encode().unwrap_or_else(|e| panic!("compile: {}", e)).0
     }
 
-    pub fn run(&self, input: Input, context: EvaluatorContext) -> RD {
+    // Fails if a function escapes, see EncodeContext
+    fn encode(&mut self) -> Result<(OpTree, u16), String> {
+        let mut ctx = EncodeContext::program();
+        let tree = self.expr.op_encode(&mut ctx);
+        ctx.check()?;
+        Ok((tree, ctx.var_slots()))
+    }
+
+    pub fn run(&self, input: impl ProgramInput<Input>, context: EvaluatorContext) -> RD {
         let mut other = self.clone();
         let code = other.op_tree().join_threshold(usize::MAX);
         let mut eval = Evaluator::new(&mut code.as_ref(), context);
-        eval.run(input.into())
+        eval.run(input.into_input())
+    }
+
+    pub fn try_run(&self, input: impl ProgramInput<Input>, context: EvaluatorContext) -> Result<RD, EvalError> {
+        let mut other = self.clone();
+        let code = other.encode().map_err(EvalError::Unsupported)?.0.join_threshold(usize::MAX);
+        let mut eval = Evaluator::new(&mut code.as_ref(), context);
+        eval.try_run(input.into_input())
+    }
+
+    // Identifies the compiled program, see program_hash
+    pub fn hash(&self) -> [u8; 32] {
+        program_hash(&self.schema, &self.clone().compile())
+    }
+
+    // Compiled without deduplication, since FETCH offsets would point into the caller
+    pub fn to_function(&self) -> FunctionValue<Input, Output> {
+        let code = self.clone().op_tree().join_threshold(usize::MAX);
+        FunctionValue(EncodedFunction(0, code), PhantomData)
     }
 
     pub fn to_string(&mut self) -> String {
@@ -49,20 +84,102 @@ impl<Input: SchemaType + Into<RD>, Output: SchemaType> Program<Input, Output> {
 }
 
 
-pub trait IntoProgram<I: SchemaType + Into<RD>, O: SchemaType>: Sized {
+// Canonical hash of a schema and compiled code (with header), as stored by add_hook
+pub fn program_hash(schema: &Schema, code: &[u8]) -> [u8; 32] {
+    solana_program::hash::hashv(&[&schema.0, code]).to_bytes()
+}
+
+
+pub trait IntoProgram<I: SchemaType, O: SchemaType>: Sized {
     fn build(&self, input: RR<I>) -> RR<O>;
     fn to_program(self) -> Program<I, O> {
         Program::from(self)
     }
 }
 
-impl<I: SchemaType + Into<RD>, O: SchemaType, F: Fn(RR<I>) -> RR<O>> IntoProgram<I, O> for F {
+impl<I: SchemaType, O: SchemaType, F: Fn(RR<I>) -> RR<O>> IntoProgram<I, O> for F {
     fn build(&self, input: RR<I>) -> RR<O> {
         self(input)
     }
 }
 
 
+/*
+ * A compiled program as a value, to be passed to another program as a
+ * Function<I, O> input. Function values that don't come from the running
+ * program are verified by the evaluator before they are called, and run with
+ * their own vars.
+ */
+#[derive(Clone, Debug)]
+pub struct FunctionValue<I, O>(EncodedFunction, PhantomData<(I, O)>);
+
+impl<I: SchemaType, O: SchemaType> FunctionValue<I, O> {
+    // From a schema and code as produced by Program::to_function, ie loaded from an account
+    pub fn from_compiled(schema: &Schema, code: Vec<u8>) -> Result<Self, String> {
+        let expected = Function::<I, O>::to_schema();
+        if *schema != expected {
+            return Err(format!("schema mismatch: expected {:?}, got {:?}", expected, schema));
+        }
+        let f = EncodedFunction(0, code);
+        verify_function(&f)?;
+        Ok(FunctionValue(f, PhantomData))
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.0.1
+    }
+}
+
+// Values accepted by Program::run as input I
+pub trait ProgramInput<I> {
+    fn into_input(self) -> RD;
+}
+impl<I: Into<RD>> ProgramInput<I> for I {
+    fn into_input(self) -> RD {
+        self.into()
+    }
+}
+impl<I: SchemaType, O: SchemaType> ProgramInput<Function<I, O>> for FunctionValue<I, O> {
+    fn into_input(self) -> RD {
+        self.0.into()
+    }
+}
+impl<I: SchemaType, O: SchemaType> ProgramInput<Function<I, O>> for Program<I, O> {
+    fn into_input(self) -> RD {
+        self.to_function().into_input()
+    }
+}
+
+
+/*
+ * Messages for ASSERT_CODE codes, kept off chain
+ */
+#[derive(Clone, Debug, Default)]
+pub struct ErrorTable(BTreeMap<u32, String>);
+
+impl ErrorTable {
+    pub fn new() -> ErrorTable {
+        Default::default()
+    }
+
+    pub fn with(mut self, code: u32, message: impl Into<String>) -> ErrorTable {
+        self.0.insert(code, message.into());
+        self
+    }
+
+    pub fn message(&self, code: u32) -> Option<&str> {
+        self.0.get(&code).map(|s| s.as_str())
+    }
+
+    pub fn describe(&self, error: &EvalError) -> String {
+        match error.code().and_then(|code| self.message(code)) {
+            Some(message) => format!("{} ({})", message, error),
+            None => error.to_string()
+        }
+    }
+}
+
+
 
 #[cfg(test)]
 mod tests {
@@ -80,4 +197,155 @@ mod tests {
         let r: u8 = prog.to_program().run(1, Default::default())._as();
         assert_eq!(r, 11);
     }
+
+    #[test]
+    fn test_error_table() {
+        let prog = (|n: RR<u8>| assert_code(n.clone().less_than(10), 6001).then(n)).to_program();
+        let errors = ErrorTable::new().with(6001, "n too large");
+        assert_eq!(prog.try_run(1, Default::default()), Ok(1u8.into()));
+        let err = prog.try_run(10, Default::default()).unwrap_err();
+        assert_eq!(err, EvalError::Code(6001));
+        assert_eq!(errors.describe(&err), "n too large (ASSERT_CODE: 6001)");
+        assert_eq!(ErrorTable::new().describe(&err), "ASSERT_CODE: 6001");
+    }
+
+    #[test]
+    fn test_compile_header() {
+        let mut prog = (|n: RR<u8>| try_(n.add(1)).m_else(0u8)).to_program();
+        let code = prog.compile();
+        let header = Header::decode(&mut code.as_ref()).unwrap();
+        assert_eq!(header.version, VERSION);
+        assert!(header.opcodes.contains(OP::TRY(Default::default()).into()));
+        assert!(!header.opcodes.contains(OP::MAP(Default::default()).into()));
+        assert_eq!(header.var_slots, 1);
+        let mut eval = Evaluator::load(&mut code.as_ref(), Default::default()).unwrap();
+        assert_eq!(eval.run(255u8.into()), 0u8.into());
+        assert!(prog.compile_version(VERSION + 1).is_err());
+
+        // Refused by evaluators that don't know the version or an opcode
+        let load = |code: Vec<u8>| Evaluator::load(&mut code.as_ref(), Default::default()).err();
+        let mut future = code.clone();
+        future[1] = VERSION + 1;
+        assert_eq!(load(future), Some(EvalError::Unsupported(format!("unsupported version: {}", VERSION + 1))));
+        let mut future = code.clone();
+        future[2] |= 2;
+        assert_eq!(load(future), Some(EvalError::Unsupported("unsupported opcodes: [01]".into())));
+        assert_eq!(load(code[Header::SIZE..].to_vec()).unwrap().to_string(), "Unsupported program: bad magic: 0x21");
+
+        // or whose header understates what the code uses
+        let mut understated = code.clone();
+        understated[2..34].fill(0);
+        assert!(str::starts_with(&load(understated).unwrap().to_string(), "Unsupported program: opcodes don't match the code"));
+        let mut understated = code.clone();
+        understated[34] = 0;
+        assert_eq!(load(understated), Some(EvalError::Unsupported("code uses 1 var slots, header has 0".into())));
+    }
+
+    #[test]
+    fn test_hash() {
+        // Repeated subtrees are deduplicated, the same way each time
+        let prog = || (|n: RR<String>| {
+            let big = "a long string".to_string();
+            n.clone().equals(big.clone()).or(n.equals(big.clone().rr().concat_str(big)))
+        }).to_program();
+        let code = prog().compile();
+        assert!(code.len() - Header::SIZE < prog().op_tree().join_threshold(usize::MAX).len());
+        assert_eq!(prog().compile(), code);
+        assert_eq!(prog().hash(), program_hash(&prog().schema, &code));
+        let other = (|n: RR<String>| n.equals("a long string".to_string())).to_program();
+        assert_ne!(other.hash(), prog().hash());
+    }
+
+    #[test]
+    fn test_function_input() {
+        let hook = (|n: RR<u64>| n.less_than(100u64)).to_program();
+        let policy = (|f: RR<Function<u64, bool>>| {
+            f.call(10u64).and(f.call(1000u64).not())
+        }).to_program();
+        assert!(policy.run(hook.clone(), Default::default())._as::<bool>());
+
+        // Round trip through bytes, the schema is checked
+        let code = hook.to_function().code().to_vec();
+        let f = FunctionValue::<u64, bool>::from_compiled(&hook.schema, code.clone()).unwrap();
+        assert!(policy.run(f, Default::default())._as::<bool>());
+        let r = FunctionValue::<u64, u64>::from_compiled(&hook.schema, code);
+        assert!(str::starts_with(&r.unwrap_err(), "schema mismatch"));
+        let r = FunctionValue::<u64, bool>::from_compiled(&hook.schema, vec![0xff]);
+        assert_eq!(r.unwrap_err(), "invalid opcode 0xff at 0");
+    }
+
+    #[test]
+    #[should_panic(expected = "CALL: invalid function: opcode 0x03 not allowed at 0")]
+    fn test_function_input_verified() {
+        let mut policy = (|f: RR<Function<u64, bool>>| f.call(10u64)).to_program();
+        let code = policy.compile();
+        let f = EncodedFunction(0, vec![OP::FETCH().into(), 0, 0]);
+        Evaluator::load(&mut code.as_ref(), Default::default()).unwrap().run(f.into());
+    }
+
+    #[test]
+    fn test_function_input_privileged() {
+        use metalock_core::vm::host::HostFn;
+        const PRICE: HostFn<(u8,), u64> = HostFn::new(1);
+        let mut ctx = EvaluatorContext::default();
+        ctx.host_functions.register(&PRICE, |args| Ok((args[0]._as::<u8>() as u64).into()));
+        let policy = (|f: RR<Function<u8, u64>>| f.call(1u8)).to_program();
+        assert_eq!(policy.try_run((|_: RR<u8>| 7u64.rr()).to_program(), ctx.clone()), Ok(7u64.into()));
+
+        // A function value can't call the host or write resources
+        let hook = (|n: RR<u8>| PRICE.call((n,))).to_program();
+        let r = policy.try_run(hook, ctx.clone());
+        assert_eq!(r, Err(EvalError::Failed("CALL: invalid function: opcode 0x90 not allowed in a function value at 0".into())));
+        let hook = (|n: RR<u8>| set_resource("price".to_string(), n).then(7u64)).to_program();
+        let r = policy.try_run(hook, ctx.clone());
+        assert!(str::starts_with(&r.unwrap_err().to_string(), "CALL: invalid function: opcode 0xad not allowed in a function value"));
+
+        // nor have the program's own functions do it
+        let hook = (|g: RR<Function<u8, u64>>| g.call(1u8)).to_program();
+        let policy = (|h: RR<Function<Function<u8, u64>, u64>>| h.call(to_function(|n: RR<u8>| PRICE.call((n,))))).to_program();
+        assert_eq!(policy.try_run(hook, ctx), Err(EvalError::Failed("opcode 0x90 not allowed in a function value".into())));
+    }
+
+    #[test]
+    fn test_function_input_vars() {
+        // The hook's SETVAR doesn't clobber the policy's let
+        let hook = (|n: RR<u64>| rr(SetVar(VarId::from(1), n.rr())).then(true)).to_program();
+        let policy = (|f: RR<Function<u64, bool>>| {
+            let_(7u64, move |x| f.call(x.clone().add(1u64)).and(x.equals(7u64)))
+        }).to_program();
+        assert!(policy.run(hook, Default::default())._as::<bool>());
+    }
+
+    #[test]
+    fn test_function_escapes() {
+        // Refused with an error rather than a panic
+        let mut prog = (|n: RR<u8>| let_(n, |k| to_function(move |x: RR<u8>| x.add(k.clone())))).to_program();
+        assert_eq!(prog.compile_version(VERSION), Err("a function refers to var 1 and may outlive it".into()));
+        let r = prog.try_run(1u8, Default::default());
+        assert_eq!(r, Err(EvalError::Unsupported("a function refers to var 1 and may outlive it".into())));
+
+        // A function value calling back sees the let_ where the program called it
+        let hook = (|g: RR<Function<u8, bool>>| g.call(3u8).and(g.call(1u8).not())).to_program();
+        let policy = (|h: RR<Function<Function<u8, bool>, bool>>| {
+            let_(2u8, move |k| h.call(to_function(move |n: RR<u8>| n.greater_than(k.clone()))))
+        }).to_program();
+        assert_eq!(policy.try_run(hook, Default::default()), Ok(true.into()));
+    }
+
+    #[test]
+    fn test_function_input_callback() {
+        // The policy's closure runs against the policy's vars when the hook calls it
+        let hook = (|g: RR<Function<u8, u8>>| g.call(1u8)).to_program();
+        let policy = (|h: RR<Function<Function<u8, u8>, u8>>| {
+            let_(5u8, move |k| h.call(to_function(move |n: RR<u8>| n.add(k.clone()))))
+        }).to_program();
+        assert_eq!(policy.try_run(hook, Default::default()), Ok(6u8.into()));
+
+        // also when called from the hook's own closure, which sees the hook's vars
+        let hook = (|g: RR<Function<u8, u8>>| vec![1u8, 2u8].rr().map(move |n| g.call(n)).sum()).to_program();
+        let policy = (|h: RR<Function<Function<u8, u8>, u8>>| {
+            let_(10u8, move |k| h.call(to_function(move |n: RR<u8>| n.add(k.clone()))))
+        }).to_program();
+        assert_eq!(policy.try_run(hook, Default::default()), Ok(23u8.into()));
+    }
 }
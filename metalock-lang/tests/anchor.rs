@@ -0,0 +1,149 @@
+#![cfg(feature = "anchor")]
+// #[program] checks features that an Anchor program crate would declare
+#![allow(unexpected_cfgs)]
+
+/*
+ * Example Anchor program keeping its hooks in a HooksAccount. Transfers are
+ * checked by the "transfer" hooks.
+ *
+ * Instructions go through the program entrypoint with accounts built in
+ * memory, standing in for a validator; rent is provided by syscall stubs.
+ * solana-program-test would run a real bank, but it isn't a dependency of
+ * the workspace and its dependency tree doesn't build offline alongside the
+ * anchor-lang checkout. Run with `cargo test --features anchor`.
+ */
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program_stubs::{set_syscall_stubs, SyscallStubs};
+use anchor_lang::{Discriminator, InstructionData};
+
+use metalock::anchor::{HooksAccount, MetalockError};
+use metalock::prelude::{assert_code, IntoProgram, ToRROrd, ToRRCommon, ToRRUnit, RR};
+
+declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
+
+#[program]
+pub mod hooks_example {
+    use super::*;
+
+    pub fn initialize(_ctx: Context<Initialize>) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn add_hook(ctx: Context<ManageHooks>, name: String, bin: Vec<u8>) -> Result<()> {
+        metalock::anchor::add_hook(&mut ctx.accounts.hooks, &ctx.accounts.owner, name, bin)?;
+        Ok(())
+    }
+
+    pub fn remove_hook(ctx: Context<ManageHooks>, id: [u8; 32]) -> Result<()> {
+        metalock::anchor::remove_hook(&mut ctx.accounts.hooks, &ctx.accounts.owner, id)
+    }
+
+    pub fn transfer(ctx: Context<Transfer>, amount: u64) -> Result<()> {
+        metalock::anchor::check_hooks(&mut ctx.accounts.hooks, Default::default(), "transfer", &amount)
+    }
+}
+
+type Hooks = HooksAccount<program::HooksExample>;
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    #[account(zero)]
+    pub hooks: Account<'info, Hooks>,
+}
+
+#[derive(Accounts)]
+pub struct ManageHooks<'info> {
+    #[account(mut)]
+    pub hooks: Account<'info, Hooks>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Transfer<'info> {
+    pub hooks: Account<'info, Hooks>,
+}
+
+
+struct Stubs;
+impl SyscallStubs for Stubs {
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        unsafe { *(var_addr as *mut Rent) = Rent::default() };
+        0
+    }
+}
+
+struct TestAccount {
+    key: Pubkey,
+    signer: bool,
+    lamports: u64,
+    data: Vec<u8>,
+    owner: Pubkey,
+}
+
+impl TestAccount {
+    fn new(signer: bool, data: Vec<u8>, owner: Pubkey) -> TestAccount {
+        TestAccount { key: Pubkey::new_unique(), signer, lamports: 1_000_000_000, data, owner }
+    }
+}
+
+fn process(accounts: &mut [&mut TestAccount], ix: impl InstructionData) -> std::result::Result<(), ProgramError> {
+    let infos: Vec<AccountInfo> = accounts.iter_mut().map(|a| {
+        AccountInfo::new(&a.key, a.signer, true, &mut a.lamports, &mut a.data, &a.owner, false, 0)
+    }).collect();
+    entry(&ID, &infos, &ix.data())
+}
+
+fn custom(e: MetalockError) -> ProgramError {
+    ProgramError::Custom(e.into())
+}
+
+fn bin(f: impl Fn(RR<u64>) -> RR<bool>) -> Vec<u8> {
+    let mut prog = f.to_program();
+    [prog.schema.0.clone(), prog.compile()].concat()
+}
+
+
+#[test]
+fn test_hooks_program() {
+    set_syscall_stubs(Box::new(Stubs));
+
+    let limit = bin(|n| n.less_than(100u64));
+    let nonzero = bin(|n| assert_code(n.clone().greater_than(0u64), 7).then(true));
+    let name = || "transfer".to_string();
+
+    // Room for two hooks
+    let space = Hooks::space(2, name().len(), limit.len().max(nonzero.len()));
+    let mut hooks = TestAccount::new(false, vec![0; space], ID);
+    let mut alice = TestAccount::new(true, vec![], Pubkey::default());
+    let mut bob = TestAccount::new(true, vec![], Pubkey::default());
+
+    process(&mut [&mut hooks], instruction::Initialize {}).unwrap();
+    assert_eq!(hooks.data[..8], Hooks::DISCRIMINATOR);
+    assert_eq!(process(&mut [&mut hooks], instruction::Initialize {}), Err(ProgramError::Custom(ErrorCode::ConstraintZero.into())));
+    process(&mut [&mut hooks], instruction::Transfer { amount: 500 }).unwrap();
+
+    let add = |bin: &Vec<u8>| instruction::AddHook { name: name(), bin: bin.clone() };
+    process(&mut [&mut hooks, &mut alice], add(&limit)).unwrap();
+    process(&mut [&mut hooks, &mut alice], instruction::Transfer { amount: 50 }).unwrap();
+    let r = process(&mut [&mut hooks], instruction::Transfer { amount: 500 });
+    assert_eq!(r, Err(custom(MetalockError::HookRejected)));
+
+    // Error codes of hooks pass through
+    process(&mut [&mut hooks, &mut bob], add(&nonzero)).unwrap();
+    assert_eq!(process(&mut [&mut hooks], instruction::Transfer { amount: 0 }), Err(ProgramError::Custom(7)));
+    let r = process(&mut [&mut hooks, &mut bob], add(&bin(|n| n.equals(1u64))));
+    assert_eq!(r, Err(custom(MetalockError::AccountFull)));
+    let r = process(&mut [&mut hooks, &mut bob], add(&vec![]));
+    assert_eq!(r, Err(custom(MetalockError::InvalidSchema)));
+
+    // Only the owner can remove a hook
+    let mut data: &[u8] = &hooks.data;
+    let account = Hooks::try_deserialize(&mut data).unwrap();
+    assert!(8 + account.space() <= space);
+    let id = *account.iter().find(|hook| *hook.owner() == alice.key).unwrap().id();
+    let r = process(&mut [&mut hooks, &mut bob], instruction::RemoveHook { id });
+    assert_eq!(r, Err(custom(MetalockError::NotOwner)));
+    process(&mut [&mut hooks, &mut alice], instruction::RemoveHook { id }).unwrap();
+    process(&mut [&mut hooks], instruction::Transfer { amount: 500 }).unwrap();
+}